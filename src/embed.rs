@@ -0,0 +1,14 @@
+// Git repository available on GitHub at https://github.com/thedarkcolour/mips-assembler
+
+// Entry points for hosts without a filesystem, like a wasm playground. Errors come back as their display text
+// so they can cross into JavaScript as plain strings.
+
+use crate::{assemble_str, disassemble_words};
+
+pub fn assemble(source: &str) -> Result<Vec<u32>, String> {
+    assemble_str(source).map_err(|err| err.to_string())
+}
+
+pub fn disassemble(words: &[u32]) -> Result<String, String> {
+    disassemble_words(words).map_err(|err| err.to_string())
+}
@@ -0,0 +1,2835 @@
+// Git repository available on GitHub at https://github.com/thedarkcolour/mips-assembler
+
+use std::collections::HashMap;
+use std::ops::Range;
+use bimap::BiMap;
+
+#[cfg(feature = "embed")]
+pub mod embed;
+pub mod register;
+
+use register::{cp0_register_name, fpu_register_name, parse_cp0_register, parse_fpu_register, Register};
+
+// Offset I type instructions
+const LB_OPCODE: u32 = 0b100000;
+const LH_OPCODE: u32 = 0b100001;
+const LW_OPCODE: u32 = 0b100011;
+const LBU_OPCODE: u32 = 0b100100;
+const LHU_OPCODE: u32 = 0b100101;
+const SB_OPCODE: u32 = 0b101000;
+const SH_OPCODE: u32 = 0b101001;
+const SW_OPCODE: u32 = 0b101011;
+// Unaligned word halves, used in pairs like "lwl $t, 3($s)" then "lwr $t, 0($s)"
+const LWL_OPCODE: u32 = 0b100010;
+const LWR_OPCODE: u32 = 0b100110;
+const SWL_OPCODE: u32 = 0b101010;
+const SWR_OPCODE: u32 = 0b101110;
+// Load linked and store conditional, for atomic read-modify-write sequences
+const LL_OPCODE: u32 = 0b110000;
+const SC_OPCODE: u32 = 0b111000;
+
+// Logical I type instructions
+const ANDI_OPCODE: u32 = 0b001100;
+const XORI_OPCODE: u32 = 0b001110;
+
+// Branch I type instructions
+const BEQ_OPCODE: u32 = 0b000100;
+const BNE_OPCODE: u32 = 0b000101;
+const BLEZ_OPCODE: u32 = 0b000110;
+const BGTZ_OPCODE: u32 = 0b000111;
+// Branches on one register against zero, told apart by the rt field
+const REGIMM_OPCODE: u32 = 0b000001;
+// rt field codes of the REGIMM traps, which compare a register with an immediate
+const TGEI_CODE: u32 = 0b01000;
+const TNEI_CODE: u32 = 0b01110;
+
+// Far more than any instruction needs, but data directives can hold a whole table on one line
+const MAX_LINE_TOKENS: usize = 1024;
+
+// .mhc container header: magic, version, endianness (0 = little, 1 = big), two reserved bytes,
+// then the base address and word count as little-endian u32s
+const MHC_MAGIC: &[u8; 4] = b"MHC\x1a";
+const MHC_VERSION: u8 = 1;
+const MHC_HEADER_LEN: usize = 16;
+
+// Used to build constants in pseudo-instructions
+const LUI_OPCODE: u32 = 0b001111;
+const ORI_OPCODE: u32 = 0b001101;
+const ADDIU_OPCODE: u32 = 0b001001;
+const ADDU_OPCODE: u32 = 0b100001;
+const SUB_OPCODE: u32 = 0b100010;
+const NOR_OPCODE: u32 = 0b100111;
+const SLT_OPCODE: u32 = 0b101010;
+
+// Expanded into real instructions by assemble_pseudo
+const PSEUDO_INSTRUCTIONS: [&str; 13] = ["b", "beqz", "bge", "bgt", "ble", "blt", "bnez", "clear", "la", "li", "move", "neg", "not"];
+
+// Data directives whose lines are laid out byte by byte rather than in whole words
+const BYTE_DIRECTIVES: [&str; 5] = [".ascii", ".asciiz", ".byte", ".half", ".space"];
+
+// Everything with a 16-bit branch offset that is the instruction's own word
+const BRANCH_INSTRUCTIONS: [&str; 13] = ["b", "bc1f", "bc1t", "beq", "beqz", "bgez", "bgezal", "bgtz", "blez", "bltz", "bltzal", "bne", "bnez"];
+
+// "sll $zero, $zero, 0", which does nothing
+const NOP_INSTRUCTION: u32 = 0;
+
+// syscall has no operands, just its func code
+const SYSCALL_INSTRUCTION: u32 = 0b001100;
+const BREAK_OPCODE: u32 = 0b001101;
+// "li $v0, 10" (assembled as "addiu $v0, $zero, 10") then syscall 10 to exit
+pub const EXIT_SEQUENCE: [u32; 2] = [0x2402000a, SYSCALL_INSTRUCTION];
+
+// Coprocessor instructions are told apart by the fmt field in bits 21-25. Moves use the same codes on every coprocessor.
+const MF_FORMAT: u32 = 0b00000;
+const MT_FORMAT: u32 = 0b00100;
+
+// System control (coprocessor 0) instructions, "mfc0 $t, $status" and friends
+const COP0_OPCODE: u32 = 0b010000;
+const COP0_INSTRUCTIONS: [&str; 3] = ["eret", "mfc0", "mtc0"];
+// Return from an exception to the address in $epc
+const ERET_INSTRUCTION: u32 = 0x42000018;
+
+// FPU (coprocessor 1) instructions
+const COP1_OPCODE: u32 = 0b010001;
+const BC1_FORMAT: u32 = 0b01000;
+// Suffixes of the arithmetic instructions, "add.s" is single precision
+const FPU_FORMATS: [(&str, u32); 3] = [("s", 0b10000), ("d", 0b10001), ("w", 0b10100)];
+// Moves and branches, which don't take a format suffix
+const COP1_INSTRUCTIONS: [&str; 4] = ["bc1f", "bc1t", "mfc1", "mtc1"];
+const LWC1_OPCODE: u32 = 0b110001;
+const SWC1_OPCODE: u32 = 0b111001;
+
+// Move word to FPU register, "mtc1 $t, $fs"
+const MTC1_INSTRUCTION: u32 = (COP1_OPCODE << 26) | (MT_FORMAT << 21);
+
+// shamt R type instructions
+const SLL_OPCODE: u32 = 0b000000;
+const SLLV_OPCODE: u32 = 0b000100;
+const SRL_OPCODE: u32 = 0b000010;
+const SRLV_OPCODE: u32 = 0b000110;
+const SRA_OPCODE: u32 = 0b000011;
+const SRAV_OPCODE: u32 = 0b000111;
+
+// R type instructions without a destination register
+const DIV_OPCODE: u32 = 0b011010;
+const DIVU_OPCODE: u32 = 0b011011;
+const JR_OPCODE: u32 = 0b001000;
+const JALR_OPCODE: u32 = 0b001001;
+const MULT_OPCODE: u32 = 0b011000;
+const MULTU_OPCODE: u32 = 0b011001;
+const MTHI_OPCODE: u32 = 0b010001;
+const MTLO_OPCODE: u32 = 0b010011;
+
+// Conditional traps, "teq $s, $t" with an optional 10-bit code
+const TGE_OPCODE: u32 = 0b110000;
+const TGEU_OPCODE: u32 = 0b110001;
+const TLT_OPCODE: u32 = 0b110010;
+const TLTU_OPCODE: u32 = 0b110011;
+const TEQ_OPCODE: u32 = 0b110100;
+const TNE_OPCODE: u32 = 0b110110;
+
+// R type instructions that only use the destination register
+const MFHI_OPCODE: u32 = 0b010000;
+const MFLO_OPCODE: u32 = 0b010010;
+
+// MIPS32 multiply-accumulate and count leading bits instructions, told apart by func code like R type ones
+const SPECIAL2_OPCODE: u32 = 0b011100;
+const MUL_OPCODE: u32 = 0b000010;
+const CLZ_OPCODE: u32 = 0b100000;
+const CLO_OPCODE: u32 = 0b100001;
+
+// MIPS32r2 additions. ext, ins and the byte shuffles live under the SPECIAL3 opcode, the rotates reuse the srl
+// and srlv func codes with a bit set in a field that used to be zero.
+const SPECIAL3_OPCODE: u32 = 0b011111;
+const EXT_OPCODE: u32 = 0b000000;
+const INS_OPCODE: u32 = 0b000100;
+const BSHFL_OPCODE: u32 = 0b100000;
+const SEB_CODE: u32 = 0b10000;
+const SEH_CODE: u32 = 0b11000;
+const WSBH_CODE: u32 = 0b00010;
+const MIPS32R2_INSTRUCTIONS: [&str; 7] = ["ext", "ins", "rotr", "rotrv", "seb", "seh", "wsbh"];
+
+// ANSI escape codes used by colorize
+const RESET_COLOR: &str = "\x1b[0m";
+const R_TYPE_COLOR: &str = "\x1b[1;32m";
+const I_TYPE_COLOR: &str = "\x1b[1;34m";
+const J_TYPE_COLOR: &str = "\x1b[1;35m";
+const REGISTER_COLOR: &str = "\x1b[33m";
+const IMMEDIATE_COLOR: &str = "\x1b[36m";
+
+// A problem with one line of source, reported alongside the line itself
+#[derive(Debug)]
+pub struct AssembleError {
+    // Only set when the source spans several files
+    pub file: Option<String>,
+    pub line_number: usize,
+    pub line: String,
+    pub message: String,
+    // The operand or mnemonic at fault, underlined when the error is shown
+    pub token: Option<String>,
+    place: TokenPlace,
+}
+
+// Where the token is, when it was cut from the line the error is reported at. Ranges are kept small since every
+// Result of the assembler carries one.
+#[derive(Debug)]
+enum TokenPlace {
+    Unknown,
+    // Its byte range in the line as it was assembled, until at() knows how much leading whitespace that had
+    Cut(Range<u32>),
+    // Its byte range in the trimmed line
+    Span(Range<u32>),
+}
+
+impl AssembleError {
+    // The line is filled in by whoever knows which line was being assembled
+    pub fn new(message: String) -> Self {
+        AssembleError { file: None, line_number: 0, line: String::new(), message, token: None, place: TokenPlace::Unknown }
+    }
+
+    // The byte range of the token at fault in the line being assembled, which at() turns into the token itself
+    pub fn span(mut self, range: Range<usize>) -> Self {
+        self.place = TokenPlace::Cut(range.start as u32..range.end as u32);
+        self
+    }
+
+    // Byte range of the token in the line, if it was cut from it
+    pub fn byte_range(&self) -> Option<Range<usize>> {
+        match &self.place {
+            TokenPlace::Span(range) => Some(range.start as usize..range.end as usize),
+            _ => None,
+        }
+    }
+
+    // Points the error at the same place in a line that had '$' inserted at the given byte positions of the original
+    fn without_insertions(mut self, positions: &[usize]) -> Self {
+        if let TokenPlace::Cut(range) = &self.place {
+            let to_original = |offset: u32| offset - positions.iter().enumerate().filter(|(index, position)| (*position + index) < offset as usize).count() as u32;
+            self.place = TokenPlace::Cut(to_original(range.start)..to_original(range.end));
+        }
+        self
+    }
+
+    // Character column and width of the token in the line. Without a span, a whole-token match is preferred over
+    // part of another one.
+    pub fn column(&self) -> Option<(usize, usize)> {
+        if let Some(span) = self.byte_range().filter(|span| !span.is_empty() && self.line.get(span.clone()).is_some()) {
+            return Some((self.line[..span.start].chars().count(), self.line[span].chars().count()));
+        }
+        let token = self.token.as_deref().filter(|token| !token.is_empty())?;
+        let separator = |c: Option<char>| c.is_none_or(|c| c.is_whitespace() || ",():#;".contains(c));
+        let mut matches = self.line.match_indices(token).map(|(start, _)| start);
+        let start = matches.clone()
+            .find(|start| separator(self.line[..*start].chars().next_back()) && separator(self.line[start + token.len()..].chars().next()))
+            .or_else(|| matches.next())?;
+
+        Some((self.line[..start].chars().count(), token.chars().count()))
+    }
+
+    pub fn at(mut self, line_number: usize, line: &str) -> Self {
+        let trimmed = line.len() - line.trim_start().len();
+        match &self.place {
+            TokenPlace::Cut(range) => {
+                let range = range.start as usize..range.end as usize;
+                self.place = match line.get(range.clone()).filter(|_| range.start >= trimmed) {
+                    Some(token) => {
+                        self.token = Some(token.to_owned());
+                        TokenPlace::Span((range.start - trimmed) as u32..(range.end - trimmed) as u32)
+                    }
+                    None => TokenPlace::Unknown,
+                };
+            }
+            // A span into some other line is no use
+            TokenPlace::Span(_) if self.line != line.trim() => self.place = TokenPlace::Unknown,
+            _ => {}
+        }
+        self.line_number = line_number;
+        self.line = line.trim().to_owned();
+        self
+    }
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}, line {}: {}\n    {}", file, self.line_number, self.message, self.line)?,
+            None => write!(f, "Line {}: {}\n    {}", self.line_number, self.message, self.line)?,
+        }
+        // Carets under the bad token, like rustc
+        if let Some((column, width)) = self.column() {
+            write!(f, "\n    {}{}", " ".repeat(column), "^".repeat(width))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+// A word whose opcode or func code isn't in any of the tables
+#[derive(Debug)]
+pub struct DisassembleError {
+    pub instruction: u32,
+}
+
+impl std::fmt::Display for DisassembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid opcode in word 0x{:08x}", self.instruction)
+    }
+}
+
+impl std::error::Error for DisassembleError {}
+
+// Where MARS and SPIM put .text, used unless another base is given
+pub const DEFAULT_TEXT_BASE: u32 = 0x00400000;
+
+// Settings that change how source is assembled
+#[derive(Clone)]
+pub struct AssembleOptions {
+    // Accept pseudo-instructions that aren't part of the standard set (li.s, li.d)
+    pub extensions: bool,
+    // Reject source lines longer than this many bytes
+    pub max_line_len: usize,
+    // Reject programs whose text and data together would be more than this many bytes
+    pub max_image_size: usize,
+    // Byte address of the first .text word
+    pub text_base: u32,
+    // Byte address of the first .data word, or None to follow straight on from the text
+    pub data_base: Option<u32>,
+    // Accept register names without their $, as in "add t0, t1, t2"
+    pub lenient_registers: bool,
+    // Byte order the words will be written in, which decides how string bytes are packed into them
+    pub big_endian: bool,
+    // End the text with EXIT_SEQUENCE unless it already ends with it. The data and its labels move up to make room.
+    pub add_exit: bool,
+    // Move the block starting at a ".globl main" label to the front of the text, so execution starts there
+    pub main_first: bool,
+}
+
+impl Default for AssembleOptions {
+    fn default() -> Self {
+        AssembleOptions { extensions: false, max_line_len: 1024, max_image_size: 0x01000000, text_base: DEFAULT_TEXT_BASE, data_base: None, lenient_registers: false, big_endian: false, add_exit: false, main_first: false }
+    }
+}
+
+// Instruction set revisions, each one a superset of the one before
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Architecture {
+    #[default]
+    Mips1,
+    Mips32,
+    Mips32r2,
+}
+
+impl Architecture {
+    pub fn name(self) -> &'static str {
+        match self {
+            Architecture::Mips1 => "MIPS I",
+            Architecture::Mips32 => "MIPS32",
+            Architecture::Mips32r2 => "MIPS32r2",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InstructionType {
+    R,
+    I,
+    J,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+// Where every line's words land, worked out before anything is assembled
+struct Layout {
+    // Section and byte address of each line
+    lines: Vec<(Section, u32)>,
+    data_base: u32,
+    // Length of each section in bytes, rounded up to whole words and including any trailing .org padding
+    text_len: u32,
+    data_len: u32,
+    errors: Vec<AssembleError>,
+    // Set when the image is over max_image_size or runs past the top of memory, so the second pass doesn't build it
+    too_large: bool,
+}
+
+// Everything a name in an operand can refer to
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    // Byte address of each label
+    pub labels: HashMap<String, u32>,
+    // Size in bytes of each .extern name, which whatever links the program places
+    pub externals: HashMap<String, u32>,
+}
+
+impl Symbols {
+    pub fn from_labels(labels: HashMap<String, u32>) -> Self {
+        Symbols { labels, externals: HashMap::new() }
+    }
+
+    fn defines(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.externals.contains_key(name)
+    }
+}
+
+// The field a reference to an .extern name leaves as 0, named like the ELF relocation types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationKind {
+    // A whole .word
+    Word,
+    // The 26-bit target of j and jal
+    Jump,
+    // A 16-bit branch offset
+    Branch,
+    // The lui and ori halves of la and li
+    High,
+    Low,
+    // Any other 16-bit immediate
+    Immediate,
+}
+
+impl RelocationKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            RelocationKind::Word => "R_MIPS_32",
+            RelocationKind::Jump => "R_MIPS_26",
+            RelocationKind::Branch => "R_MIPS_PC16",
+            RelocationKind::High => "R_MIPS_HI16",
+            RelocationKind::Low => "R_MIPS_LO16",
+            RelocationKind::Immediate => "R_MIPS_16",
+        }
+    }
+}
+
+// A word at a byte address that still needs an .extern name's address added in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub address: u32,
+    pub kind: RelocationKind,
+    pub symbol: String,
+}
+
+// An assembled program, its sections kept apart so each can be placed at its own base
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub text: Vec<u32>,
+    pub data: Vec<u32>,
+    pub text_base: u32,
+    pub data_base: u32,
+    pub symbols: Symbols,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Program {
+    // The image, text first and then data
+    pub fn words(&self) -> Vec<u32> {
+        let mut words = self.text.clone();
+        words.extend(&self.data);
+        words
+    }
+}
+
+// Holds the instruction and register tables so they are only built once
+pub struct Assembler {
+    j_codes: BiMap<&'static str, u32>,
+    i_codes: BiMap<&'static str, u32>,
+    r_codes: BiMap<&'static str, u32>,
+    regimm_codes: BiMap<&'static str, u32>,
+    fpu_codes: BiMap<&'static str, u32>,
+    special2_codes: BiMap<&'static str, u32>,
+    architecture: Architecture,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler::with_architecture(Architecture::default())
+    }
+
+    // Also accepts and decodes the instructions added up to the given revision
+    pub fn with_architecture(architecture: Architecture) -> Self {
+        Assembler {
+            j_codes: create_j_codes(),
+            i_codes: create_i_codes(),
+            r_codes: create_r_codes(),
+            regimm_codes: create_regimm_codes(),
+            fpu_codes: create_fpu_codes(),
+            special2_codes: create_special2_codes(),
+            architecture,
+        }
+    }
+
+    // Assembles already-read source lines, resolving labels first. Every bad line is reported, not just the first.
+    pub fn assemble_lines(&self, lines: &[String], options: &AssembleOptions) -> Result<Vec<u32>, Vec<AssembleError>> {
+        self.assemble_program(lines, options).map(|program| program.words())
+    }
+
+    // Like assemble_lines, but keeps the sections, symbols and the relocations left by .extern names
+    pub fn assemble_program(&self, lines: &[String], options: &AssembleOptions) -> Result<Program, Vec<AssembleError>> {
+        if options.main_first {
+            if let Some(order) = main_first_order(lines).map_err(|err| vec![err])? {
+                let reordered: Vec<String> = order.iter().map(|line_index| lines[*line_index].clone()).collect();
+                let program = self.assemble_program(&reordered, &AssembleOptions { main_first: false, ..options.clone() }).map_err(|mut errors| {
+                    // Back to the line numbers of the source as written
+                    for err in &mut errors {
+                        if err.line_number > 0 {
+                            err.line_number = order[err.line_number - 1] + 1;
+                        }
+                    }
+                    errors.sort_by_key(|err| err.line_number);
+                    errors
+                })?;
+                // An .org at the start of the block could still keep it off the base
+                if program.symbols.labels["main"] != options.text_base {
+                    let message = format!("main is at 0x{:08x}, not the text base 0x{:08x}", program.symbols.labels["main"], options.text_base);
+                    return Err(vec![AssembleError::new(message).at(order[0] + 1, &lines[order[0]])]);
+                }
+                return Ok(program);
+            }
+        }
+        // Only known to be needed once the text is assembled
+        if options.add_exit {
+            let program = self.assemble_program(lines, &AssembleOptions { add_exit: false, ..options.clone() })?;
+            if program.text.ends_with(&EXIT_SEQUENCE) {
+                return Ok(program);
+            }
+        }
+        let mut errors = Vec::new();
+
+        // First pass finds where every line and label is, second pass assembles with them
+        let layout = layout(lines, options);
+        let (symbols, symbol_errors) = collect_symbols(lines, options);
+        errors.extend(symbol_errors);
+        if layout.too_large {
+            errors.sort_by_key(|err| err.line_number);
+            return Err(errors);
+        }
+
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        let mut relocations = Vec::new();
+        for (line_index, (asm_line, (section, address))) in lines.iter().zip(&layout.lines).enumerate() {
+            // Already reported by the layout
+            if asm_line.len() > options.max_line_len {
+                continue;
+            }
+
+            let (bytes, base) = match section {
+                Section::Text => (&mut text, options.text_base),
+                Section::Data => (&mut data, layout.data_base),
+            };
+            // Fills the gap left by an .org or alignment
+            let offset = (address - base) as usize;
+            if bytes.len() < offset {
+                bytes.resize(offset, 0);
+            }
+
+            match self.line_bytes(asm_line, *address, options, &symbols).and_then(|line_bytes| Ok((line_bytes, line_relocations(asm_line, *address, &symbols)?))) {
+                Ok((line_bytes, line_relocations)) => {
+                    bytes.extend(line_bytes);
+                    relocations.extend(line_relocations);
+                }
+                Err(err) => errors.push(err.at(line_index + 1, asm_line)),
+            }
+        }
+
+        if errors.is_empty() {
+            text.resize(layout.text_len as usize, 0);
+            data.resize(layout.data_len as usize, 0);
+            let mut text = words_from_bytes(&text, options.big_endian);
+            let data = words_from_bytes(&data, options.big_endian);
+            if options.add_exit {
+                // The layout left room for it
+                let exit_start = text.len() - EXIT_SEQUENCE.len();
+                text[exit_start..].copy_from_slice(&EXIT_SEQUENCE);
+            }
+            relocations.sort_by_key(|relocation| relocation.address);
+            Ok(Program { text, data, text_base: options.text_base, data_base: layout.data_base, symbols, relocations })
+        } else {
+            errors.sort_by_key(|err| err.line_number);
+            Err(errors)
+        }
+    }
+
+    // What a line adds to its section. The byte directives give exactly their bytes, everything else whole words in
+    // the output byte order.
+    fn line_bytes(&self, asm_line: &str, address: u32, options: &AssembleOptions, symbols: &Symbols) -> Result<Vec<u8>, AssembleError> {
+        let parts = tokens(asm_line)?;
+        let parts = label_definition(&parts).1;
+        if let Some(directive) = parts.first().map(|directive| directive.to_ascii_lowercase()).filter(|directive| BYTE_DIRECTIVES.contains(&directive.as_str())) {
+            return directive_bytes(&directive, parts, symbols, options);
+        }
+
+        let words = if options.lenient_registers {
+            self.assemble_lenient_line(asm_line, address, options, symbols)?
+        } else {
+            self.assemble_line(asm_line, address, options, symbols)?
+        };
+        Ok(mhc_bytes(&words, options.big_endian))
+    }
+
+    // Assembles one line as if it were at the given byte address. Labels map to byte addresses.
+    pub fn assemble_line(&self, asm_line: &str, address: u32, options: &AssembleOptions, symbols: &Symbols) -> Result<Vec<u32>, AssembleError> {
+        let parts = tokens(asm_line)?;
+        let parts = label_definition(&parts).1.to_vec();
+        // Blank lines, comments and labels don't produce any words
+        if parts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let instruction = parts[0].to_ascii_lowercase();
+        let instruction = instruction.as_str();
+        if instruction.starts_with('.') {
+            return assemble_directive(instruction, &parts, symbols, options);
+        }
+
+        let float_constant = options.extensions && (instruction == "li.s" || instruction == "li.d");
+        let mips32r2 = MIPS32R2_INSTRUCTIONS.contains(&instruction);
+        let special2_code = self.special2_codes.get_by_left(instruction);
+        let required = if mips32r2 {
+            Architecture::Mips32r2
+        } else if special2_code.is_some() {
+            Architecture::Mips32
+        } else {
+            Architecture::Mips1
+        };
+        if required > self.architecture {
+            return Err(AssembleError::new(format!("{} is a {} instruction", instruction, required.name())).span(parts[0].range()));
+        }
+        let known = self.i_codes.contains_left(instruction) || self.r_codes.contains_left(instruction) || self.regimm_codes.contains_left(instruction) || self.j_codes.contains_left(instruction) || self.fpu_instruction(instruction).is_some() || COP1_INSTRUCTIONS.contains(&instruction) || COP0_INSTRUCTIONS.contains(&instruction) || special2_code.is_some() || mips32r2 || float_constant || instruction == "nop" || PSEUDO_INSTRUCTIONS.contains(&instruction);
+        let expected = operand_count(instruction);
+        if known && !expected.contains(&(parts.len() - 1)) {
+            let expected = if expected.start() == expected.end() {
+                format!("{} operand{}", expected.start(), if *expected.start() == 1 { "" } else { "s" })
+            } else {
+                format!("{} to {} operands", expected.start(), expected.end())
+            };
+            return Err(AssembleError::new(format!("{} expects {}, found {}", instruction, expected, parts.len() - 1)));
+        }
+
+        if let Some(i_opcode) = self.i_codes.get_by_left(instruction) {
+            Ok(vec![assemble_i(*i_opcode, parts, symbols, address)?])
+        } else if let Some(r_opcode) = self.r_codes.get_by_left(instruction) {
+            Ok(vec![assemble_r(*r_opcode, parts)?])
+        } else if let Some(rt_code) = self.regimm_codes.get_by_left(instruction) {
+            if (TGEI_CODE..=TNEI_CODE).contains(rt_code) {
+                Ok(vec![assemble_trap_immediate(*rt_code, parts, symbols)?])
+            } else {
+                Ok(vec![assemble_branch(REGIMM_OPCODE, *rt_code, parts, symbols, address)?])
+            }
+        } else if let Some(j_opcode) = self.j_codes.get_by_left(instruction) {
+            Ok(vec![assemble_j(*j_opcode, parts[1], symbols, address)?])
+        } else if let Some((func_code, format)) = self.fpu_instruction(instruction) {
+            Ok(vec![assemble_fpu(instruction, func_code, format, parts)?])
+        } else if COP1_INSTRUCTIONS.contains(&instruction) {
+            Ok(vec![assemble_cop1(instruction, parts, symbols, address)?])
+        } else if COP0_INSTRUCTIONS.contains(&instruction) {
+            Ok(vec![assemble_cop0(instruction, parts)?])
+        } else if let Some(func_code) = special2_code {
+            Ok(vec![assemble_special2(*func_code, parts)?])
+        } else if mips32r2 {
+            Ok(vec![assemble_mips32r2(instruction, parts)?])
+        } else if float_constant {
+            assemble_float_constant(instruction, parts)
+        } else if instruction == "nop" {
+            Ok(vec![NOP_INSTRUCTION])
+        } else if PSEUDO_INSTRUCTIONS.contains(&instruction) {
+            assemble_pseudo(instruction, parts, symbols, address)
+        } else {
+            Err(AssembleError::new(format!("unknown instruction {}", parts[0])).span(parts[0].range()))
+        }
+    }
+
+    // Rewrites a line so operands that are register names without their $ have one, "lw t0, 4(sp)" becomes
+    // "lw $t0, 4($sp)". Labels win over registers, and bare numbers are left alone since they are immediates.
+    pub fn prefix_registers(&self, asm_line: &str, symbols: &Symbols) -> Result<String, AssembleError> {
+        let positions = self.register_positions(asm_line, symbols)?;
+        Ok(insert_dollars(asm_line, &positions))
+    }
+
+    // assemble_line for a line that may leave the $ off registers. Errors point into the line as written.
+    pub fn assemble_lenient_line(&self, asm_line: &str, address: u32, options: &AssembleOptions, symbols: &Symbols) -> Result<Vec<u32>, AssembleError> {
+        let positions = self.register_positions(asm_line, symbols)?;
+        let prefixed = insert_dollars(asm_line, &positions);
+        self.assemble_line(&prefixed, address, options, symbols).map_err(|err| err.without_insertions(&positions))
+    }
+
+    // Byte positions of the operands prefix_registers puts a $ in front of, in order
+    fn register_positions(&self, asm_line: &str, symbols: &Symbols) -> Result<Vec<usize>, AssembleError> {
+        let parts = tokens(asm_line)?;
+        let skipped = parts.len() - label_definition(&parts).1.len();
+        if parts.get(skipped).is_none_or(|mnemonic| mnemonic.starts_with('.')) {
+            return Ok(Vec::new());
+        }
+
+        let is_register = |name: &str| {
+            let with_dollar = format!("${}", name);
+            !name.starts_with(['$', '\'', '"']) && !name.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+                && !symbols.defines(name)
+                && (Register::parse(&with_dollar).is_some() || parse_fpu_register(&with_dollar).is_some() || parse_cp0_register(&with_dollar).is_some())
+        };
+        let mut positions = Vec::new();
+        for operand in &parts[skipped + 1..] {
+            let name = offset_base(*operand).map_or(*operand, |(_, base)| base);
+            if is_register(&name) {
+                positions.push(name.start);
+            }
+        }
+        Ok(positions)
+    }
+
+    // Func code and fmt of an FPU arithmetic mnemonic like "add.s" or "cvt.d.w". Conversions need a source format
+    // other than their target, everything else only works on floats.
+    fn fpu_instruction(&self, instruction: &str) -> Option<(u32, u32)> {
+        let (operation, suffix) = instruction.rsplit_once('.')?;
+        let func_code = *self.fpu_codes.get_by_left(operation)?;
+        let (_, format) = FPU_FORMATS.iter().find(|(name, _)| *name == suffix)?;
+        let valid = match operation.strip_prefix("cvt.") {
+            Some(target) => target != suffix,
+            None => suffix != "w",
+        };
+
+        valid.then_some((func_code, *format))
+    }
+
+    // None if the word isn't one of the FPU instructions we assemble, including any with nonzero unused fields
+    fn disassemble_cop1(&self, instruction: u32, address: u32, symbols: &HashMap<u32, String>) -> Option<String> {
+        let format = (instruction >> 21) & 0b11111;
+        let t_field = (instruction >> 16) & 0b11111;
+        let s_field = (instruction >> 11) & 0b11111;
+        let d_field = (instruction >> 6) & 0b11111;
+
+        match format {
+            MF_FORMAT | MT_FORMAT if instruction & 0x7ff == 0 => {
+                let instruction_name = if format == MF_FORMAT { "mfc1" } else { "mtc1" };
+                Some(format!("{} {}, {}", instruction_name, Register::from_field(t_field).name(), fpu_register_name(s_field)))
+            }
+            // Only condition flag 0, so the rt field is just the true/false bit
+            BC1_FORMAT if t_field <= 1 => {
+                let instruction_name = if t_field == 1 { "bc1t" } else { "bc1f" };
+                let offset = instruction as u16 as i16;
+                let target = address.wrapping_add(4).wrapping_add((offset as i32 * 4) as u32);
+                match symbols.get(&target) {
+                    Some(label) => Some(format!("{} {}", instruction_name, label)),
+                    None => Some(format!("{} {}", instruction_name, offset)),
+                }
+            }
+            _ => {
+                let (suffix, _) = FPU_FORMATS.iter().find(|(_, code)| *code == format)?;
+                let operation = self.fpu_codes.get_by_right(&(instruction & 0b111111))?;
+                let instruction_name = format!("{}.{}", operation, suffix);
+                self.fpu_instruction(&instruction_name)?;
+
+                let (fd, fs, ft) = (fpu_register_name(d_field), fpu_register_name(s_field), fpu_register_name(t_field));
+                match fpu_operand_count(operation) {
+                    _ if operation.starts_with("c.") => (d_field == 0).then(|| format!("{} {}, {}", instruction_name, fs, ft)),
+                    2 => (t_field == 0).then(|| format!("{} {}, {}", instruction_name, fd, fs)),
+                    _ => Some(format!("{} {}, {}, {}", instruction_name, fd, fs, ft)),
+                }
+            }
+        }
+    }
+
+    // None for unknown func codes and for words with a nonzero field the instruction doesn't use
+    fn special2_line(&self, instruction: u32) -> Option<String> {
+        let fields = decode(instruction);
+        let instruction_name = self.special2_codes.get_by_right(&fields.funct)?;
+        let t_register = Register::from_field(fields.rt).name();
+        let s_register = Register::from_field(fields.rs).name();
+        let d_register = Register::from_field(fields.rd).name();
+
+        if fields.shamt != 0 {
+            return None;
+        }
+        match fields.funct {
+            MUL_OPCODE => Some(format!("{} {}, {}, {}", instruction_name, d_register, s_register, t_register)),
+            // The destination is written to both rt and rd
+            CLZ_OPCODE | CLO_OPCODE => (fields.rt == fields.rd).then(|| format!("{} {}, {}", instruction_name, d_register, s_register)),
+            _ => (fields.rd == 0).then(|| format!("{} {}, {}", instruction_name, s_register, t_register)),
+        }
+    }
+
+    // The disassembly of a MIPS32r2 word, or None for anything else or when that revision isn't enabled
+    fn mips32r2_line(&self, instruction: u32) -> Option<String> {
+        if self.architecture < Architecture::Mips32r2 {
+            return None;
+        }
+
+        let fields = decode(instruction);
+        let t_register = Register::from_field(fields.rt).name();
+        let s_register = Register::from_field(fields.rs).name();
+        let d_register = Register::from_field(fields.rd).name();
+
+        match (fields.opcode, fields.funct) {
+            (0, SRL_OPCODE) if fields.rs == 1 => Some(format!("rotr {}, {}, {}", d_register, t_register, fields.shamt)),
+            (0, SRLV_OPCODE) if fields.shamt == 1 => Some(format!("rotrv {}, {}, {}", d_register, t_register, s_register)),
+            // ext stores size - 1 in rd, ins stores the last bit it writes
+            (SPECIAL3_OPCODE, EXT_OPCODE) => Some(format!("ext {}, {}, {}, {}", t_register, s_register, fields.shamt, fields.rd + 1)),
+            (SPECIAL3_OPCODE, INS_OPCODE) if fields.rd >= fields.shamt => Some(format!("ins {}, {}, {}, {}", t_register, s_register, fields.shamt, fields.rd - fields.shamt + 1)),
+            (SPECIAL3_OPCODE, BSHFL_OPCODE) if fields.rs == 0 => {
+                let instruction_name = match fields.shamt {
+                    SEB_CODE => "seb",
+                    SEH_CODE => "seh",
+                    WSBH_CODE => "wsbh",
+                    _ => return None,
+                };
+                Some(format!("{} {}, {}", instruction_name, d_register, t_register))
+            }
+            _ => None,
+        }
+    }
+
+    // The source form of one word, or an error if its opcode or func code is unknown
+    pub fn disassemble_word(&self, instruction: u32) -> Result<String, DisassembleError> {
+        self.disassemble_with_symbols(instruction, 0, &HashMap::new())
+    }
+
+    // Like disassemble_word, but jumps and branches to an address in the symbol table print the label instead
+    pub fn disassemble_with_symbols(&self, instruction: u32, address: u32, symbols: &HashMap<u32, String>) -> Result<String, DisassembleError> {
+        let opcode = instruction >> 26;
+        if let Some(line) = self.mips32r2_line(instruction) {
+            return Ok(line);
+        }
+
+        if instruction == NOP_INSTRUCTION {
+            Ok("nop".to_owned())
+        } else if opcode == 0 {
+            let r_instruction = self.r_codes.get_by_right(&(instruction & 0b111111)).ok_or(DisassembleError { instruction })?;
+            Ok(disassemble_r(instruction, r_instruction))
+        } else if opcode == REGIMM_OPCODE {
+            let rt_code = (instruction >> 16) & 0b11111;
+            let regimm_instruction = self.regimm_codes.get_by_right(&rt_code).ok_or(DisassembleError { instruction })?;
+            if (TGEI_CODE..=TNEI_CODE).contains(&rt_code) {
+                let s_register = Register::from_field(instruction >> 21).name();
+                Ok(format!("{} {}, {}", regimm_instruction, s_register, instruction as u16 as i16))
+            } else {
+                Ok(disassemble_branch(instruction, regimm_instruction, address, symbols))
+            }
+        } else if opcode == COP1_OPCODE {
+            self.disassemble_cop1(instruction, address, symbols).ok_or(DisassembleError { instruction })
+        } else if opcode == COP0_OPCODE {
+            disassemble_cop0(instruction).ok_or(DisassembleError { instruction })
+        } else if opcode == SPECIAL2_OPCODE && self.architecture >= Architecture::Mips32 {
+            self.special2_line(instruction).ok_or(DisassembleError { instruction })
+        } else if let Some(j_instruction) = self.j_codes.get_by_right(&opcode) {
+            Ok(disassemble_j(instruction, j_instruction, address, symbols))
+        } else if let Some(i_instruction) = self.i_codes.get_by_right(&opcode) {
+            Ok(disassemble_i(instruction, i_instruction, address, symbols))
+        } else {
+            Err(DisassembleError { instruction })
+        }
+    }
+
+    // The format of a word, or None if no known instruction encodes to it
+    pub fn instruction_type(&self, instruction: u32) -> Option<InstructionType> {
+        let opcode = instruction >> 26;
+        if self.mips32r2_line(instruction).is_some() {
+            // ext and ins keep their bit positions in rd and shamt, but the fields still line up
+            return Some(InstructionType::R);
+        }
+
+        if opcode == 0 {
+            self.r_codes.contains_right(&(instruction & 0b111111)).then_some(InstructionType::R)
+        } else if opcode == REGIMM_OPCODE {
+            self.regimm_codes.contains_right(&((instruction >> 16) & 0b11111)).then_some(InstructionType::I)
+        } else if opcode == COP1_OPCODE {
+            // FPU branches carry an offset like the I type ones, everything else fills the R type fields
+            let branch = (instruction >> 21) & 0b11111 == BC1_FORMAT;
+            self.disassemble_cop1(instruction, 0, &HashMap::new()).map(|_| if branch { InstructionType::I } else { InstructionType::R })
+        } else if opcode == COP0_OPCODE {
+            disassemble_cop0(instruction).map(|_| InstructionType::R)
+        } else if opcode == SPECIAL2_OPCODE && self.architecture >= Architecture::Mips32 {
+            self.special2_line(instruction).map(|_| InstructionType::R)
+        } else if self.j_codes.contains_right(&opcode) {
+            Some(InstructionType::J)
+        } else {
+            self.i_codes.contains_right(&opcode).then_some(InstructionType::I)
+        }
+    }
+
+    // The word's bits split into its fields with spaces, e.g. "100011 01001 01000 0000000000000100" for lw.
+    // Words that aren't instructions stay in one group.
+    pub fn grouped_bits(&self, instruction: u32) -> String {
+        let bits = format!("{:032b}", instruction);
+        let widths: &[usize] = match self.instruction_type(instruction) {
+            Some(InstructionType::R) => &[6, 5, 5, 5, 5, 6],
+            Some(InstructionType::I) => &[6, 5, 5, 16],
+            Some(InstructionType::J) => &[6, 26],
+            None => &[32],
+        };
+
+        let mut groups = Vec::with_capacity(widths.len());
+        let mut start = 0;
+        for width in widths {
+            groups.push(&bits[start..start + width]);
+            start += width;
+        }
+        groups.join(" ")
+    }
+
+    // Wraps the tokens of a disassembled line in ANSI colors: the mnemonic by instruction type, then registers and immediates
+    pub fn colorize(&self, instruction: u32, line: &str) -> String {
+        let mnemonic_color = match self.instruction_type(instruction) {
+            Some(InstructionType::R) => R_TYPE_COLOR,
+            Some(InstructionType::J) => J_TYPE_COLOR,
+            _ => I_TYPE_COLOR,
+        };
+
+        let mut colored = String::new();
+        let mut token = String::new();
+        let mut first_token = true;
+        // A trailing separator flushes the last token
+        for c in line.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() || c == '$' || c == '.' || c == '-' || c == '_' {
+                token.push(c);
+                continue;
+            }
+            if !token.is_empty() {
+                let token_color = if first_token {
+                    mnemonic_color
+                } else if token.starts_with('$') {
+                    REGISTER_COLOR
+                } else {
+                    IMMEDIATE_COLOR
+                };
+                colored.push_str(token_color);
+                colored.push_str(&token);
+                colored.push_str(RESET_COLOR);
+                token.clear();
+                first_token = false;
+            }
+            colored.push(c);
+        }
+
+        // Drop the separator added above
+        colored.pop();
+        colored
+    }
+
+    // Field breakdown like "opcode=0x00 rs=$a1(5) rt=$a2(6) rd=$a0(4) shamt=0 funct=0x20 (add)"
+    pub fn format_fields(&self, instruction: u32) -> String {
+        let fields = decode(instruction);
+        let register = |number: u32| format!("{}({})", Register::from_field(number).name(), number);
+
+        if fields.opcode == 0 || fields.opcode == SPECIAL2_OPCODE || fields.opcode == SPECIAL3_OPCODE {
+            let line = if fields.opcode == SPECIAL2_OPCODE { self.special2_line(instruction) } else { self.mips32r2_line(instruction) };
+            let name = line.as_deref().and_then(|line| line.split(' ').next())
+                .or(if fields.opcode == 0 { self.r_codes.get_by_right(&fields.funct).copied() } else { None })
+                .unwrap_or("unknown");
+            format!("opcode=0x{:02x} rs={} rt={} rd={} shamt={} funct=0x{:02x} ({})", fields.opcode, register(fields.rs), register(fields.rt), register(fields.rd), fields.shamt, fields.funct, name)
+        } else if fields.opcode == COP0_OPCODE || fields.opcode == COP1_OPCODE {
+            let name = self.disassemble_word(instruction).map_or("unknown".to_owned(), |line| line.split(' ').next().unwrap().to_owned());
+            format!("opcode=0x{:02x} fmt=0x{:02x} ft={} fs={} fd={} funct=0x{:02x} ({})", fields.opcode, fields.rs, fields.rt, fields.rd, fields.shamt, fields.funct, name)
+        } else if let Some(name) = self.j_codes.get_by_right(&fields.opcode) {
+            format!("opcode=0x{:02x} target=0x{:07x} ({})", fields.opcode, fields.target, name)
+        } else {
+            let name = if fields.opcode == REGIMM_OPCODE { self.regimm_codes.get_by_right(&fields.rt) } else { self.i_codes.get_by_right(&fields.opcode) };
+            let name = name.unwrap_or(&"unknown");
+            format!("opcode=0x{:02x} rs={} rt={} imm=0x{:04x} ({})", fields.opcode, register(fields.rs), register(fields.rt), fields.immediate, name)
+        }
+    }
+}
+
+// Assembles a whole program with the default options, returning the first error if there are any
+pub fn assemble_str(source: &str) -> Result<Vec<u32>, AssembleError> {
+    let lines: Vec<String> = source.lines().map(str::to_owned).collect();
+
+    Assembler::new().assemble_lines(&lines, &AssembleOptions::default())
+        .map_err(|errors| errors.into_iter().next().unwrap())
+}
+
+// One line of disassembly per word
+pub fn disassemble_words(words: &[u32]) -> Result<String, DisassembleError> {
+    let assembler = Assembler::new();
+    let mut text = String::new();
+
+    for word in words {
+        text.push_str(&assembler.disassemble_word(*word)?);
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+// .bin files hold one instruction per line, written as 32 '0'/'1' characters, which may be grouped with spaces
+pub fn read_bin(text: &str) -> Result<Vec<u32>, Vec<AssembleError>> {
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+
+    for (line_index, text_line) in text.lines().enumerate() {
+        let line: String = text_line.chars().filter(|c| *c != ' ').collect();
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.len() == 32 && line.chars().all(|c| c == '0' || c == '1') {
+            instructions.push(u32::from_str_radix(line, 2).unwrap());
+        } else {
+            errors.push(AssembleError::new(format!("expected 32 binary digits, found {} characters", line.chars().count())).at(line_index + 1, text_line));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
+}
+
+// One hex word per line, with or without 0x, as printed when assembling to stdout
+pub fn read_hex(text: &str) -> Result<Vec<u32>, Vec<AssembleError>> {
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_hex_word(line) {
+            Ok(instruction) => instructions.push(instruction),
+            Err(err) => errors.push(AssembleError::new(format!("invalid hex word: {}", err)).at(line_index + 1, line)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
+}
+
+// A truncated last word has its missing bytes read as zeros, so any input decodes without panicking
+pub fn words_from_bytes(bytes: &[u8], big_endian: bool) -> Vec<u32> {
+    let mut instructions = Vec::with_capacity(bytes.len().div_ceil(4));
+
+    for chunk in bytes.chunks(4) {
+        // Rust wants things in sized slices apparently
+        let mut chunk_4 = [0u8; 4];
+        chunk_4[..chunk.len()].copy_from_slice(chunk);
+        instructions.push(if big_endian { u32::from_be_bytes(chunk_4) } else { u32::from_le_bytes(chunk_4) });
+    }
+
+    instructions
+}
+
+// Reads a .mhc file, configuring itself from the header if there is one and falling back to legacy headerless words
+// in the given byte order. The base address is the header's, None without one.
+pub fn read_mhc(bytes: &[u8], big_endian: bool) -> Result<(Vec<u32>, Option<u32>), String> {
+    if bytes.len() < MHC_HEADER_LEN || !bytes.starts_with(MHC_MAGIC) {
+        return Ok((words_from_bytes(bytes, big_endian), None));
+    }
+    if bytes[4] != MHC_VERSION {
+        return Err(format!("unsupported MHC version {}", bytes[4]));
+    }
+
+    let big_endian = bytes[5] == 1;
+    let base_address = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    let word_count = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]) as usize;
+    let body = &bytes[MHC_HEADER_LEN..];
+    if body.len() != word_count * 4 {
+        return Err(format!("MHC header says {} words but the file holds {} bytes of them", word_count, body.len()));
+    }
+
+    Ok((words_from_bytes(body, big_endian), Some(base_address)))
+}
+
+pub fn parse_hex_word(word: &str) -> Result<u32, std::num::ParseIntError> {
+    let digits = word.strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .unwrap_or(word);
+
+    u32::from_str_radix(digits, 16)
+}
+
+// Every field of an instruction word, whichever format it actually uses
+pub struct InstructionFields {
+    pub opcode: u32,
+    pub rs: u32,
+    pub rt: u32,
+    pub rd: u32,
+    pub shamt: u32,
+    pub funct: u32,
+    pub immediate: u32,
+    pub target: u32,
+}
+
+pub fn decode(instruction: u32) -> InstructionFields {
+    InstructionFields {
+        opcode: instruction >> 26,
+        rs: (instruction >> 21) & 0b11111,
+        rt: (instruction >> 16) & 0b11111,
+        rd: (instruction >> 11) & 0b11111,
+        shamt: (instruction >> 6) & 0b11111,
+        funct: instruction & 0b111111,
+        immediate: instruction & 0xffff,
+        target: instruction & 0x03ffffff,
+    }
+}
+
+// Maps each label to the byte address it points at and records the .extern names. Lines that fail to tokenize, and
+// bad .extern lines, are reported by the second pass.
+pub fn collect_symbols(lines: &[String], options: &AssembleOptions) -> (Symbols, Vec<AssembleError>) {
+    let layout = layout(lines, options);
+    let mut symbols = Symbols::default();
+    let mut errors = layout.errors;
+
+    for (line_index, (asm_line, (_, address))) in lines.iter().zip(&layout.lines).enumerate() {
+        if asm_line.len() > options.max_line_len {
+            continue;
+        }
+        let Ok(parts) = tokens(asm_line) else {
+            continue;
+        };
+
+        let (label, parts) = label_definition(&parts);
+        if let Some(label) = label {
+            if symbols.labels.insert(label.to_string(), *address).is_some() {
+                errors.push(AssembleError::new(format!("label {} is already defined", label)).span(label.range()).at(line_index + 1, asm_line));
+            }
+        }
+        if let [directive, name, size] = parts {
+            if directive.eq_ignore_ascii_case(".extern") && is_name(name) {
+                if let Some(size) = extern_size(size) {
+                    symbols.externals.insert(name.to_string(), size);
+                }
+            }
+        }
+    }
+
+    (symbols, errors)
+}
+
+// The order to lay out lines in so the block starting at main comes first. The block runs up to the next section
+// directive. None when main isn't declared with .globl or is already first in the text.
+fn main_first_order(lines: &[String]) -> Result<Option<Vec<usize>>, AssembleError> {
+    let mut section = Section::Text;
+    let mut global = false;
+    let mut start = None;
+    let mut end = lines.len();
+    // The last two text lines before main that assemble to something
+    let mut before_main: Vec<(usize, String)> = Vec::new();
+
+    for (line_index, asm_line) in lines.iter().enumerate() {
+        let Ok(parts) = tokens(asm_line) else {
+            continue;
+        };
+        let (label, parts) = label_definition(&parts);
+        if label.is_some_and(|label| label.text == "main") {
+            if section == Section::Data {
+                return Err(AssembleError::new("main must be in .text to go first".to_owned()).at(line_index + 1, asm_line));
+            }
+            start = Some(line_index);
+        }
+        let Some(mnemonic) = parts.first().map(|mnemonic| mnemonic.to_ascii_lowercase()) else {
+            continue;
+        };
+
+        match mnemonic.as_str() {
+            ".text" | ".data" => {
+                if start.is_some() && end == lines.len() {
+                    end = line_index;
+                }
+                section = if mnemonic == ".text" { Section::Text } else { Section::Data };
+            }
+            ".globl" | ".global" => global |= parts[1..].iter().any(|label| label.text == "main"),
+            ".extern" => {}
+            _ if start.is_none() && section == Section::Text => {
+                before_main.push((line_index, mnemonic));
+                if before_main.len() > 2 {
+                    before_main.remove(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(start) = start.filter(|_| global && !before_main.is_empty()) else {
+        return Ok(None);
+    };
+    // Moved behind main, the code before it would otherwise run on into whatever follows. A jump in the second to
+    // last place covers its delay slot.
+    let transfers = |mnemonic: &str| matches!(mnemonic, "j" | "jr" | "b" | "eret");
+    if !before_main.iter().any(|(_, mnemonic)| transfers(mnemonic)) {
+        let line_index = before_main[before_main.len() - 1].0;
+        return Err(AssembleError::new("the code before main falls through into it, end it with a jump to move main first".to_owned()).at(line_index + 1, &lines[line_index]));
+    }
+
+    Ok(Some((start..end).chain(0..start).chain(end..lines.len()).collect()))
+}
+
+// The byte address each line is assembled at
+pub fn line_addresses(lines: &[String], options: &AssembleOptions) -> Vec<u32> {
+    layout(lines, options).lines.iter().map(|(_, address)| *address).collect()
+}
+
+// Tracks a byte offset per section, switched by .text/.data and moved forward by .org. Each line starts at its own
+// alignment, so consecutive .byte, .half and .ascii data is packed together.
+fn layout(lines: &[String], options: &AssembleOptions) -> Layout {
+    let mut offsets = Vec::with_capacity(lines.len());
+    let mut errors = Vec::new();
+    let mut section = Section::Text;
+    // Wider than an address so a runaway .space or .org can't overflow before it is reported
+    let mut text_len = 0u64;
+    let mut data_len = 0u64;
+    let mut too_large = false;
+
+    for (line_index, asm_line) in lines.iter().enumerate() {
+        let offset = if section == Section::Text { &mut text_len } else { &mut data_len };
+        // Where the line starts and ends
+        offsets.push((section, *offset, *offset));
+        // Checked before tokenizing so a huge line is never split up
+        if asm_line.len() > options.max_line_len {
+            let message = format!("line is {} bytes long, the maximum is {}", asm_line.len(), options.max_line_len);
+            let start: String = asm_line.chars().take(40).collect();
+            errors.push(AssembleError::new(message).at(line_index + 1, &(start + "...")));
+            continue;
+        }
+        let Ok(parts) = tokens(asm_line) else {
+            continue;
+        };
+        let parts = label_definition(&parts).1;
+        if parts.is_empty() {
+            continue;
+        }
+
+        match parts[0].to_ascii_lowercase().as_str() {
+            ".text" => section = Section::Text,
+            ".data" => section = Section::Data,
+            // Like GNU as, the origin is a byte offset from the start of the current section
+            ".org" => match parts.get(1).and_then(|origin| parse_immediate(origin)).filter(|origin| (0..=0x3fffffff).contains(origin)) {
+                Some(origin) if parts.len() == 2 => {
+                    let origin = origin as u64;
+                    if origin < *offset {
+                        errors.push(AssembleError::new(format!(".org {} is behind the current offset 0x{:x}", parts[1], *offset)).span(parts[1].range()).at(line_index + 1, asm_line));
+                    } else {
+                        *offset = origin;
+                        if let Some(line_offset) = offsets.last_mut() {
+                            line_offset.2 = origin;
+                        }
+                    }
+                }
+                _ => errors.push(AssembleError::new(".org expects 1 byte offset".to_owned()).at(line_index + 1, asm_line)),
+            },
+            _ => {
+                let (alignment, size) = line_size(parts, options.extensions);
+                // Labels on the line point at the aligned start
+                *offset = offset.next_multiple_of(alignment as u64);
+                let start = *offset;
+                *offset += size as u64;
+                if let Some(line_offset) = offsets.last_mut() {
+                    line_offset.1 = start;
+                    line_offset.2 = *offset;
+                }
+            }
+        }
+
+        let image_size = text_len + data_len;
+        if image_size > options.max_image_size as u64 && !too_large {
+            too_large = true;
+            let message = format!("the image would be {} bytes, the maximum is {}", image_size, options.max_image_size);
+            errors.push(AssembleError::new(message).span(parts[0].range()).at(line_index + 1, asm_line));
+        }
+    }
+
+    text_len = text_len.next_multiple_of(4);
+    data_len = data_len.next_multiple_of(4);
+    if options.add_exit {
+        text_len += EXIT_SEQUENCE.len() as u64 * 4;
+    }
+    // Each section has to end by the top of the address space. The error goes on the first line past it, or the last
+    // line of the section when only the padding at its end is.
+    let data_start = options.data_base.map_or(options.text_base as u64 + text_len, u64::from);
+    for (section, base, len) in [(Section::Text, options.text_base as u64, text_len), (Section::Data, data_start, data_len)] {
+        if len == 0 || base + len <= 1 << 32 {
+            continue;
+        }
+        let room = (1u64 << 32).saturating_sub(base);
+        let mut section_lines = offsets.iter().enumerate().filter(|(_, (line_section, _, _))| *line_section == section);
+        let line_index = section_lines.clone()
+            .find(|(_, (_, _, end))| *end > room)
+            .or_else(|| section_lines.next_back())
+            .map_or(lines.len().saturating_sub(1), |(line_index, _)| line_index);
+        let name = if section == Section::Text { ".text" } else { ".data" };
+        let message = format!("{} runs past the top of the address space, from 0x{:08x} there is only room for {} bytes", name, base, room);
+        errors.push(AssembleError::new(message).at(line_index + 1, lines.get(line_index).map_or("", String::as_str)));
+        too_large = true;
+    }
+    // Only an image that is too large, and already reported, doesn't fit
+    let text_len = u32::try_from(text_len).unwrap_or(u32::MAX);
+    let data_len = u32::try_from(data_len).unwrap_or(u32::MAX);
+    let data_base = options.data_base.unwrap_or(options.text_base.wrapping_add(text_len));
+    let lines = offsets.into_iter()
+        .map(|(section, offset, _)| (section, if section == Section::Text { options.text_base } else { data_base }.wrapping_add(offset as u32)))
+        .collect();
+
+    Layout { lines, data_base, text_len, data_len, errors, too_large }
+}
+
+
+// The line with a '$' inserted at each byte position, which are in order
+fn insert_dollars(asm_line: &str, positions: &[usize]) -> String {
+    let mut line = String::with_capacity(asm_line.len() + positions.len());
+    let mut copied = 0;
+    for position in positions {
+        line.push_str(&asm_line[copied..*position]);
+        line.push('$');
+        copied = *position;
+    }
+    line.push_str(&asm_line[copied..]);
+    line
+}
+
+// Splits off a leading "name:", leaving the instruction after it (if any)
+fn label_definition<'a, 'b>(parts: &'b [Token<'a>]) -> (Option<Token<'a>>, &'b [Token<'a>]) {
+    match parts {
+        [first, rest @ ..] if first.ends_with(':') => (first.strip_suffix(':'), rest),
+        _ => (None, parts),
+    }
+}
+
+// The alignment and size in bytes of a line, needed before labels can be resolved
+fn line_size(parts: &[Token], extensions: bool) -> (u32, u32) {
+    let count = parts.len() as u32 - 1;
+    match parts[0].to_ascii_lowercase().as_str() {
+        ".globl" | ".global" | ".extern" => (1, 0),
+        ".half" => (2, count * 2),
+        ".byte" => (1, count),
+        // A bad string is reported by the second pass
+        ".ascii" | ".asciiz" => (1, string_bytes(&parts[1..], parts[0].eq_ignore_ascii_case(".asciiz")).map_or(0, |bytes| bytes.len() as u32)),
+        ".space" => (1, parts.get(1).and_then(|size| parse_immediate(size)).map_or(0, |size| size.clamp(0, 0x3fffffff) as u32)),
+        _ => (4, line_word_count(parts, extensions) * 4),
+    }
+}
+
+// How many words an instruction or .word line assembles to
+fn line_word_count(parts: &[Token], extensions: bool) -> u32 {
+    match parts[0].to_ascii_lowercase().as_str() {
+        "li.s" if extensions => 3,
+        "li.d" if extensions => 6,
+        "la" => 2,
+        // slt into $at, then a branch on it
+        "blt" | "bgt" | "ble" | "bge" => 2,
+        ".word" => parts.len() as u32 - 1,
+        // A bad constant is reported by the second pass. Labels aren't placed yet, so anything but a literal might
+        // need both halves.
+        "li" => parts.get(2).and_then(|value| parse_immediate(value)).map_or(2, li_word_count),
+        _ => 1,
+    }
+}
+
+// Constants that fit in 16 bits take a single addiu or ori, anything else needs lui first
+fn li_word_count(value: i64) -> u32 {
+    if (-0x8000..=0xffff).contains(&value) { 1 } else { 2 }
+}
+
+// The exact bytes of a .mhc file
+pub fn mhc_bytes(words: &[u32], big_endian: bool) -> Vec<u8> {
+    words.iter().flat_map(|word| if big_endian { word.to_be_bytes() } else { word.to_le_bytes() }).collect()
+}
+
+pub fn mhc_header(word_count: usize, big_endian: bool, base_address: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(MHC_HEADER_LEN);
+
+    header.extend_from_slice(MHC_MAGIC);
+    // Version, endianness, two reserved bytes
+    header.extend_from_slice(&[MHC_VERSION, big_endian as u8, 0, 0]);
+    header.extend_from_slice(&base_address.to_le_bytes());
+    header.extend_from_slice(&(word_count as u32).to_le_bytes());
+
+    header
+}
+
+// Intel HEX records for each (base address, words) segment, 16 bytes per data record.
+// Extended linear address records switch the upper 16 address bits whenever they change, such as between a text at
+// 0x00400000 and data at 0x10010000.
+pub fn ihex_string(segments: &[(u32, &[u32])], big_endian: bool) -> String {
+    fn record(out: &mut String, address: u16, record_type: u8, data: &[u8]) {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, record_type];
+        bytes.extend_from_slice(data);
+        // Two's complement of the byte sum
+        let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+        bytes.push(checksum);
+
+        out.push(':');
+        for byte in bytes {
+            out.push_str(&format!("{:02X}", byte));
+        }
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    for (base_address, words) in segments {
+        let bytes = mhc_bytes(words, big_endian);
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let address = base_address.wrapping_add(offset as u32);
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                record(&mut out, 0, 0x04, &((address >> 16) as u16).to_be_bytes());
+            }
+            // Records never cross a 64K boundary
+            let room = 0x10000 - (address & 0xffff) as usize;
+            let len = 16.min(bytes.len() - offset).min(room);
+            record(&mut out, address as u16, 0x00, &bytes[offset..offset + len]);
+            offset += len;
+        }
+    }
+    record(&mut out, 0, 0x01, &[]);
+
+    out
+}
+
+// Standard CRC-32 (IEEE 802.3, same as zip/gzip), bit by bit since images are small
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+// A token and the bytes of the line it was cut from, which errors point at. Parts of a token keep their own range,
+// and text that isn't in the line, like the zero offset of "($sp)", has an empty one.
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+impl<'a> Token<'a> {
+    fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    // The bytes of the token in the given range of its text
+    fn slice(&self, range: Range<usize>) -> Token<'a> {
+        Token { text: &self.text[range.clone()], start: self.start + range.start, end: self.start + range.end }
+    }
+
+    fn trim(&self) -> Token<'a> {
+        let start = self.text.len() - self.text.trim_start().len();
+        self.slice(start..self.text.trim_end().len().max(start))
+    }
+
+    fn strip_suffix(&self, suffix: char) -> Option<Token<'a>> {
+        self.text.strip_suffix(suffix).map(|text| self.slice(0..text.len()))
+    }
+}
+
+impl std::ops::Deref for Token<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.text
+    }
+}
+
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.text)
+    }
+}
+
+// tokenize, with where each token came from
+fn tokens(asm_line: &str) -> Result<Vec<Token<'_>>, AssembleError> {
+    Ok(tokenize_spans(asm_line)?.into_iter().map(|part| Token { text: &asm_line[part.clone()], start: part.start, end: part.end }).collect())
+}
+
+// Splits a line into its mnemonic and operands, dropping any comment
+pub fn tokenize(asm_line: &str) -> Result<Vec<&str>, AssembleError> {
+    Ok(tokenize_spans(asm_line)?.into_iter().map(|part| &asm_line[part]).collect())
+}
+
+// The byte range of each token tokenize would give
+pub fn tokenize_spans(asm_line: &str) -> Result<Vec<Range<usize>>, AssembleError> {
+    let mut parts = Vec::new();
+    let mut token_start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut end = asm_line.len();
+
+    // split on commas or any whitespace (tabs too), except inside quotes, which may hold separators, # or ;
+    for (index, c) in asm_line.char_indices() {
+        if let Some(quote_char) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote_char {
+                quote = None;
+            }
+            continue;
+        }
+
+        // Both # and ; start comments
+        if c == '#' || c == ';' {
+            end = index;
+            break;
+        }
+        if c == ',' || c.is_whitespace() {
+            if let Some(start) = token_start.take() {
+                push_token(&mut parts, asm_line, start..index)?;
+            }
+            continue;
+        }
+        if token_start.is_none() {
+            token_start = Some(index);
+        }
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+        }
+    }
+    if let Some(start) = token_start {
+        push_token(&mut parts, asm_line, start..end)?;
+    }
+
+    Ok(parts)
+}
+
+// "end - start" and "label+ 4" are one operand. Only whitespace can be joined, never a comma. Gives up as soon as
+// the line has too many tokens, so a runaway line isn't split any further.
+fn push_token(parts: &mut Vec<Range<usize>>, asm_line: &str, part: Range<usize>) -> Result<(), AssembleError> {
+    if let Some(previous) = parts.last_mut() {
+        let token = &asm_line[part.clone()];
+        let joins = !asm_line[previous.end..part.start].contains(',');
+        if joins && (asm_line[previous.clone()].ends_with(['+', '-']) || token == "-" || token.starts_with('+')) {
+            previous.end = part.end;
+            return Ok(());
+        }
+    }
+    if parts.len() == MAX_LINE_TOKENS {
+        return Err(AssembleError::new(format!("too many operands, at most {} tokens are allowed per line", MAX_LINE_TOKENS)));
+    }
+
+    parts.push(part);
+    Ok(())
+}
+
+fn register(name: Token) -> Result<u32, AssembleError> {
+    Register::parse(&name).map(Register::number).ok_or_else(|| {
+        if !name.starts_with('$') && Register::parse(&format!("${}", name)).is_some() {
+            AssembleError::new(format!("unknown register {}, did you mean ${}?", name, name)).span(name.range())
+        } else {
+            AssembleError::new(format!("unknown register {}", name)).span(name.range())
+        }
+    })
+}
+
+fn fpu_register(name: Token) -> Result<u32, AssembleError> {
+    parse_fpu_register(&name).ok_or_else(|| AssembleError::new(format!("invalid FPU register {}, expected $f0 to $f31", name)).span(name.range()))
+}
+
+// Operands of an FPU arithmetic instruction without its format suffix, "abs" or "cvt.d" rather than "abs.s"
+fn fpu_operand_count(operation: &str) -> usize {
+    if operation.starts_with("c.") || operation.starts_with("cvt.") || matches!(operation, "abs" | "mov" | "neg") { 2 } else { 3 }
+}
+
+// Number of operands each mnemonic takes, checked before any of them are parsed
+fn operand_count(instruction: &str) -> std::ops::RangeInclusive<usize> {
+    match instruction {
+        "nop" | "syscall" | "eret" => 0..=0,
+        // The break code is optional
+        "break" => 0..=1,
+        // The link register is optional and defaults to $ra
+        "jalr" => 1..=2,
+        "madd" | "maddu" | "msub" | "msubu" | "clz" | "clo" => 2..=2,
+        "ext" | "ins" => 4..=4,
+        "seb" | "seh" | "wsbh" => 2..=2,
+        // The trap code is optional
+        "teq" | "tge" | "tgeu" | "tlt" | "tltu" | "tne" => 2..=3,
+        "teqi" | "tgei" | "tgeiu" | "tlti" | "tltiu" | "tnei" => 2..=2,
+        "j" | "jal" | "jr" | "b" | "clear" | "mfhi" | "mflo" | "mthi" | "mtlo" | "bc1f" | "bc1t" => 1..=1,
+        "div" | "divu" | "mult" | "multu" | "bgez" | "bgezal" | "bgtz" | "blez" | "bltz" | "bltzal" | "li" | "la" | "move" | "not" | "neg" | "beqz" | "bnez" | "lui" | "lb" | "lbu" | "lh" | "lhu" | "lw" | "sb" | "sh" | "sw" | "lwl" | "lwr" | "swl" | "swr" | "ll" | "sc" | "li.s" | "li.d" | "lwc1" | "swc1" | "mfc1" | "mtc1" | "mfc0" | "mtc0" => 2..=2,
+        _ if instruction.rsplit_once('.').is_some_and(|(operation, _)| fpu_operand_count(operation) == 2) => 2..=2,
+        _ => 3..=3,
+    }
+}
+
+// Data directives: ".word N, N..." emits each value verbatim (labels give their byte address). The byte directives
+// emit their bytes zero padded to a whole word, a program packs them together instead.
+fn assemble_directive(directive: &str, parts: &[Token], symbols: &Symbols, options: &AssembleOptions) -> Result<Vec<u32>, AssembleError> {
+    if BYTE_DIRECTIVES.contains(&directive) {
+        return Ok(pack_bytes(&directive_bytes(directive, parts, symbols, options)?, options.big_endian));
+    }
+
+    match directive {
+        ".word" => {
+            if parts.len() == 1 {
+                return Err(AssembleError::new(".word expects at least 1 value".to_owned()));
+            }
+            parts[1..].iter().map(|value| {
+                let (word, _) = evaluate(*value, symbols)?;
+                if !(-0x80000000..=0xffffffff).contains(&word) {
+                    return Err(AssembleError::new(format!("word {} doesn't fit in 32 bits", value)).span(value.range()));
+                }
+                Ok(word as u32)
+            }).collect()
+        }
+        // Handled while laying out the sections
+        ".text" | ".data" if parts.len() == 1 => Ok(Vec::new()),
+        ".text" | ".data" => Err(AssembleError::new(format!("{} expects 0 operands, found {}", directive, parts.len() - 1))),
+        ".org" => Ok(Vec::new()),
+        // Every label is visible anyway, so this only checks that the names exist
+        ".globl" | ".global" => {
+            if parts.len() == 1 {
+                return Err(AssembleError::new(format!("{} expects at least 1 label", directive)));
+            }
+            match parts[1..].iter().find(|label| !symbols.labels.contains_key(label.text)) {
+                Some(label) => Err(AssembleError::new(format!("undefined label {}", label)).span(label.range())),
+                None => Ok(Vec::new()),
+            }
+        }
+        // The names were collected with the labels, this checks the operands
+        ".extern" => {
+            if parts.len() != 3 {
+                return Err(AssembleError::new(format!(".extern expects 2 operands, found {}", parts.len() - 1)));
+            }
+            if !is_name(&parts[1]) {
+                return Err(AssembleError::new(format!("invalid .extern name {}", parts[1])).span(parts[1].range()));
+            }
+            if symbols.labels.contains_key(parts[1].text) {
+                return Err(AssembleError::new(format!("{} is both a label and .extern", parts[1])).span(parts[1].range()));
+            }
+            extern_size(&parts[2]).ok_or_else(|| AssembleError::new(format!("invalid .extern size {}", parts[2])).span(parts[2].range()))?;
+            Ok(Vec::new())
+        }
+        _ => Err(AssembleError::new(format!("unknown directive {}", parts[0])).span(parts[0].range())),
+    }
+}
+
+// ".space N" emits N zero bytes, ".byte", ".half", ".ascii" and ".asciiz" emit their values' bytes in memory order
+fn directive_bytes(directive: &str, parts: &[Token], symbols: &Symbols, options: &AssembleOptions) -> Result<Vec<u8>, AssembleError> {
+    match directive {
+        ".ascii" | ".asciiz" => {
+            if parts.len() == 1 {
+                return Err(AssembleError::new(format!("{} expects at least 1 string", directive)));
+            }
+            string_bytes(&parts[1..], directive == ".asciiz")
+        }
+        ".byte" | ".half" => {
+            if parts.len() == 1 {
+                return Err(AssembleError::new(format!("{} expects at least 1 value", directive)));
+            }
+            let (size, range) = if directive == ".byte" { (1, -0x80..=0xff) } else { (2, -0x8000..=0xffff) };
+            let mut bytes = Vec::new();
+            for value in &parts[1..] {
+                let (number, _) = evaluate(*value, symbols)?;
+                if !range.contains(&number) {
+                    return Err(AssembleError::new(format!("{} doesn't fit in {} bits", value, size * 8)).span(value.range()));
+                }
+                // Halves in the same byte order as the words
+                let number = number as u16;
+                match (size, options.big_endian) {
+                    (1, _) => bytes.push(number as u8),
+                    (_, true) => bytes.extend_from_slice(&number.to_be_bytes()),
+                    (_, false) => bytes.extend_from_slice(&number.to_le_bytes()),
+                }
+            }
+            Ok(bytes)
+        }
+        ".space" => {
+            if parts.len() != 2 {
+                return Err(AssembleError::new(format!(".space expects 1 operand, found {}", parts.len() - 1)));
+            }
+            let size = parse_immediate(&parts[1])
+                .filter(|size| (0..=0x3fffffff).contains(size))
+                .ok_or_else(|| AssembleError::new(format!("invalid .space size {}", parts[1])).span(parts[1].range()))?;
+            // A program's layout already checked its total size, a line on its own is checked here
+            if size as usize > options.max_image_size {
+                return Err(AssembleError::new(format!(".space {} is more than the maximum image size of {} bytes", parts[1], options.max_image_size)).span(parts[1].range()));
+            }
+            Ok(vec![0; size as usize])
+        }
+        _ => unreachable!("{} isn't a byte directive", directive),
+    }
+}
+
+// "move $d, $s" is addu from $zero. li and la build their constant with lui/ori through $at, like MARS does.
+// The other expansions also follow MARS: "not" is nor with $zero, "neg" subtracts from $zero and "clear" is addu of
+// $zero. b, beqz and bnez compare with $zero, and blt/bgt/ble/bge set $at with slt and branch on it.
+fn assemble_pseudo(instruction: &str, parts: Vec<Token>, symbols: &Symbols, address: u32) -> Result<Vec<u32>, AssembleError> {
+    let at_register = Register::At.number();
+    let branch = |opcode: u32, s_register: u32, t_register: u32, address: u32| -> Result<u32, AssembleError> {
+        Ok(branch_offset(parts[parts.len() - 1], symbols, address)? | (t_register << 16) | (s_register << 21) | (opcode << 26))
+    };
+
+    match instruction {
+        "b" => return Ok(vec![branch(BEQ_OPCODE, 0, 0, address)?]),
+        "beqz" => return Ok(vec![branch(BEQ_OPCODE, register(parts[1])?, 0, address)?]),
+        "bnez" => return Ok(vec![branch(BNE_OPCODE, register(parts[1])?, 0, address)?]),
+        "blt" | "bgt" | "ble" | "bge" => {
+            let s_register = register(parts[1])?;
+            let t_register = register(parts[2])?;
+            // "a > b" is "b < a", and ble/bge branch when the opposite slt is false
+            let (less, greater) = if matches!(instruction, "blt" | "bge") { (s_register, t_register) } else { (t_register, s_register) };
+            let opcode = if matches!(instruction, "blt" | "bgt") { BNE_OPCODE } else { BEQ_OPCODE };
+            return Ok(vec![
+                SLT_OPCODE | (at_register << 11) | (greater << 16) | (less << 21),
+                // The branch is the second word, so its offset counts from one word later
+                branch(opcode, at_register, 0, address.wrapping_add(4))?,
+            ]);
+        }
+        _ => {}
+    }
+
+    let t_register = register(parts[1])?;
+    let value = match instruction {
+        "move" => return Ok(vec![ADDU_OPCODE | (t_register << 11) | (register(parts[2])? << 16)]),
+        "clear" => return Ok(vec![ADDU_OPCODE | (t_register << 11)]),
+        "not" => return Ok(vec![NOR_OPCODE | (t_register << 11) | (register(parts[2])? << 21)]),
+        "neg" => return Ok(vec![SUB_OPCODE | (t_register << 11) | (register(parts[2])? << 16)]),
+        _ => evaluate(parts[2], symbols)?.0,
+    };
+    if !(-0x80000000..=0xffffffff).contains(&value) {
+        return Err(AssembleError::new(format!("constant {} doesn't fit in 32 bits", parts[2])).span(parts[2].range()));
+    }
+
+    let bits = value as u32;
+    // Layout only sized li from a literal, expressions always take lui/ori
+    if instruction == "li" && parse_immediate(&parts[2]).is_some_and(|value| li_word_count(value) == 1) {
+        // addiu sign extends, so the top half of the unsigned range goes through ori instead
+        return Ok(vec![if value <= 0x7fff {
+            (bits & 0xffff) | (t_register << 16) | (ADDIU_OPCODE << 26)
+        } else {
+            bits | (t_register << 16) | (ORI_OPCODE << 26)
+        }]);
+    }
+
+    Ok(vec![
+        (bits >> 16) | (at_register << 16) | (LUI_OPCODE << 26),
+        (bits & 0xffff) | (t_register << 16) | (at_register << 21) | (ORI_OPCODE << 26),
+    ])
+}
+
+// li.s/li.d pseudo-instructions. The IEEE-754 bits are built in $at with lui/ori then moved over with mtc1.
+fn assemble_float_constant(instruction: &str, parts: Vec<Token>) -> Result<Vec<u32>, AssembleError> {
+    let at_register = Register::At.number();
+    let f_register = fpu_register(parts[1])?;
+    let invalid_constant = || AssembleError::new(format!("invalid floating point constant {}", parts[2])).span(parts[2].range());
+
+    // Doubles occupy an even/odd register pair, low word in the even register
+    let halves: Vec<(u32, u32)> = if instruction == "li.d" {
+        if !f_register.is_multiple_of(2) {
+            return Err(AssembleError::new(format!("li.d needs an even FPU register, found {}", parts[1])).span(parts[1].range()));
+        }
+        let bits = parts[2].parse::<f64>().map_err(|_| invalid_constant())?.to_bits();
+        vec![(bits as u32, f_register), ((bits >> 32) as u32, f_register + 1)]
+    } else {
+        let bits = parts[2].parse::<f32>().map_err(|_| invalid_constant())?.to_bits();
+        vec![(bits, f_register)]
+    };
+
+    Ok(halves.into_iter().flat_map(|(bits, f_register)| [
+        (bits >> 16) | (at_register << 16) | (LUI_OPCODE << 26),
+        (bits & 0xffff) | (at_register << 16) | (at_register << 21) | (ORI_OPCODE << 26),
+        MTC1_INSTRUCTION | (at_register << 16) | (f_register << 11),
+    ]).collect())
+}
+
+// "op.fmt $fd, $fs, $ft", or "$fd, $fs" for moves and conversions and "$fs, $ft" for comparisons, which always set
+// condition flag 0
+fn assemble_fpu(instruction: &str, func_code: u32, format: u32, parts: Vec<Token>) -> Result<u32, AssembleError> {
+    let operation = instruction.rsplit_once('.').map_or(instruction, |(operation, _)| operation);
+    let (d_register, s_register, t_register) = if operation.starts_with("c.") {
+        (0, fpu_register(parts[1])?, fpu_register(parts[2])?)
+    } else if fpu_operand_count(operation) == 2 {
+        (fpu_register(parts[1])?, fpu_register(parts[2])?, 0)
+    } else {
+        (fpu_register(parts[1])?, fpu_register(parts[2])?, fpu_register(parts[3])?)
+    };
+
+    Ok(func_code | (d_register << 6) | (s_register << 11) | (t_register << 16) | (format << 21) | (COP1_OPCODE << 26))
+}
+
+// "bc1t target" and "bc1f target" branch on condition flag 0, "mfc1 $t, $fs" and "mtc1 $t, $fs" copy bits unchanged
+fn assemble_cop1(instruction: &str, parts: Vec<Token>, symbols: &Symbols, address: u32) -> Result<u32, AssembleError> {
+    match instruction {
+        "bc1f" | "bc1t" => {
+            let true_bit = (instruction == "bc1t") as u32;
+            Ok(branch_offset(parts[1], symbols, address)? | (true_bit << 16) | (BC1_FORMAT << 21) | (COP1_OPCODE << 26))
+        }
+        _ => {
+            let format = if instruction == "mfc1" { MF_FORMAT } else { MT_FORMAT };
+            Ok((fpu_register(parts[2])? << 11) | (register(parts[1])? << 16) | (format << 21) | (COP1_OPCODE << 26))
+        }
+    }
+}
+
+// "mfc0 $t, $cp0" and "mtc0 $t, $cp0" copy a system control register, "eret" has no operands
+fn assemble_cop0(instruction: &str, parts: Vec<Token>) -> Result<u32, AssembleError> {
+    if instruction == "eret" {
+        return Ok(ERET_INSTRUCTION);
+    }
+
+    let format = if instruction == "mfc0" { MF_FORMAT } else { MT_FORMAT };
+    let d_register = parse_cp0_register(&parts[2])
+        .ok_or_else(|| AssembleError::new(format!("invalid coprocessor 0 register {}", parts[2])).span(parts[2].range()))?;
+
+    Ok((d_register << 11) | (register(parts[1])? << 16) | (format << 21) | (COP0_OPCODE << 26))
+}
+
+// None for anything but the CP0 instructions we assemble, such as moves with a select field
+fn disassemble_cop0(instruction: u32) -> Option<String> {
+    if instruction == ERET_INSTRUCTION {
+        return Some("eret".to_owned());
+    }
+
+    let instruction_name = match (instruction >> 21) & 0b11111 {
+        MF_FORMAT => "mfc0",
+        MT_FORMAT => "mtc0",
+        _ => return None,
+    };
+    (instruction & 0x7ff == 0).then(|| format!("{} {}, {}", instruction_name, Register::from_field(instruction >> 16).name(), cp0_register_name(instruction >> 11)))
+}
+
+// "mul $d, $s, $t" writes the low word to a register, "madd $s, $t" and the rest accumulate into hi and lo, and
+// "clz $d, $s" counts leading zeros
+fn assemble_special2(func_code: u32, parts: Vec<Token>) -> Result<u32, AssembleError> {
+    let (d_register, s_register, t_register) = match func_code {
+        MUL_OPCODE => (register(parts[1])?, register(parts[2])?, register(parts[3])?),
+        CLZ_OPCODE | CLO_OPCODE => {
+            let d_register = register(parts[1])?;
+            (d_register, register(parts[2])?, d_register)
+        }
+        _ => (0, register(parts[1])?, register(parts[2])?),
+    };
+
+    Ok(func_code | (d_register << 11) | (t_register << 16) | (s_register << 21) | (SPECIAL2_OPCODE << 26))
+}
+
+// "ext $t, $s, pos, size" and "ins $t, $s, pos, size" copy a bit field, "seb $d, $t", "seh $d, $t" and
+// "wsbh $d, $t" rearrange bytes, "rotr $d, $t, amount" and "rotrv $d, $t, $s" rotate right
+fn assemble_mips32r2(instruction: &str, parts: Vec<Token>) -> Result<u32, AssembleError> {
+    let constant = |index: usize, range: std::ops::RangeInclusive<i64>, what: &str| {
+        parse_immediate(&parts[index])
+            .filter(|value| range.contains(value))
+            .map(|value| value as u32)
+            .ok_or_else(|| AssembleError::new(format!("invalid {} {}, expected {} to {}", what, parts[index], range.start(), range.end())).span(parts[index].range()))
+    };
+
+    match instruction {
+        "ext" | "ins" => {
+            let position = constant(3, 0..=31, "bit position")?;
+            let size = constant(4, 1..=32, "bit field size")?;
+            if position + size > 32 {
+                return Err(AssembleError::new(format!("bit field of {} bits at bit {} doesn't fit in a word", size, position)).span(parts[4].range()));
+            }
+            let (d_field, func_code) = if instruction == "ext" { (size - 1, EXT_OPCODE) } else { (position + size - 1, INS_OPCODE) };
+            Ok(func_code | (position << 6) | (d_field << 11) | (register(parts[1])? << 16) | (register(parts[2])? << 21) | (SPECIAL3_OPCODE << 26))
+        }
+        "seb" | "seh" | "wsbh" => {
+            let shuffle_code = match instruction {
+                "seb" => SEB_CODE,
+                "seh" => SEH_CODE,
+                _ => WSBH_CODE,
+            };
+            Ok(BSHFL_OPCODE | (shuffle_code << 6) | (register(parts[1])? << 11) | (register(parts[2])? << 16) | (SPECIAL3_OPCODE << 26))
+        }
+        "rotr" => {
+            let amount = constant(3, 0..=31, "rotate amount")?;
+            Ok(SRL_OPCODE | (amount << 6) | (register(parts[1])? << 11) | (register(parts[2])? << 16) | (1 << 21))
+        }
+        _ => Ok(SRLV_OPCODE | (1 << 6) | (register(parts[1])? << 11) | (register(parts[2])? << 16) | (register(parts[3])? << 21)),
+    }
+}
+
+fn assemble_i(opcode: u32, parts: Vec<Token>, symbols: &Symbols, address: u32) -> Result<u32, AssembleError> {
+    let immediate: u32;
+    let t_register: u32;
+    let s_register: u32;
+
+    if offset_opcode(opcode) {
+        let (offset, base) = offset_base(parts[2])
+            .ok_or_else(|| AssembleError::new(format!("expected offset($register), found {}", parts[2])).span(parts[2].range()))?;
+
+        // lwc1 and swc1 move between memory and an FPU register
+        t_register = if opcode == LWC1_OPCODE || opcode == SWC1_OPCODE { fpu_register(parts[1])? } else { register(parts[1])? };
+        // Offsets are signed, so "-4($sp)" is stored as the two's complement 0xfffc
+        immediate = immediate_16(offset, false, symbols)?;
+        s_register = register(base)?;
+    } else if opcode == LUI_OPCODE {
+        // "lui $t, imm" has no source register
+        immediate = immediate_16(parts[2], true, symbols)?;
+        t_register = register(parts[1])?;
+        s_register = 0;
+    } else if opcode == BLEZ_OPCODE || opcode == BGTZ_OPCODE {
+        return assemble_branch(opcode, 0, parts, symbols, address);
+    } else if opcode == BEQ_OPCODE || opcode == BNE_OPCODE {
+        // Branches compare "$s, $t", the opposite order to "addi $t, $s, imm"
+        immediate = branch_offset(parts[3], symbols, address)?;
+        s_register = register(parts[1])?;
+        t_register = register(parts[2])?;
+    } else {
+        immediate = immediate_16(parts[3], zero_extended(opcode), symbols)?;
+        t_register = register(parts[1])?;
+        s_register = register(parts[2])?;
+    }
+
+    Ok(immediate | (t_register << 16) | (s_register << 21) | (opcode << 26))
+}
+
+// Splits "offset($base)" into its two halves. A missing offset, as in "($sp)", is zero.
+fn offset_base(operand: Token) -> Option<(Token, Token)> {
+    let open = operand.find('(')?;
+    let (offset, base) = (operand.slice(0..open), operand.slice(open + 1..operand.len()).strip_suffix(')')?);
+    if base.is_empty() || base.contains(['(', ')']) || offset.contains(')') {
+        return None;
+    }
+
+    Some((if offset.is_empty() { Token { text: "0", ..offset } } else { offset }, base))
+}
+
+// Loads and stores, which all take "$t, offset($s)"
+fn offset_opcode(opcode: u32) -> bool {
+    matches!(opcode, LB_OPCODE | LH_OPCODE | LW_OPCODE | LBU_OPCODE | LHU_OPCODE | SB_OPCODE | SH_OPCODE | SW_OPCODE | LWL_OPCODE | LWR_OPCODE | SWL_OPCODE | SWR_OPCODE | LL_OPCODE | SC_OPCODE | LWC1_OPCODE | SWC1_OPCODE)
+}
+
+// Decimal, 0x hex, 0b binary or 0o octal, optionally negative, or a character literal like 'A'
+fn parse_immediate(text: &str) -> Option<i64> {
+    if let Some(literal) = text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        return char_literal(literal);
+    }
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if let Some(octal) = digits.strip_prefix("0o").or_else(|| digits.strip_prefix("0O")) {
+        i64::from_str_radix(octal, 8).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+// "a + b - c" where each term is a number or a label's address. The flag is set when a label was used, which makes
+// the value an address rather than a plain number. .extern names count as 0 and leave a relocation instead.
+fn evaluate(text: Token, symbols: &Symbols) -> Result<(i64, bool), AssembleError> {
+    let terms = expression_terms(text);
+
+    let mut total = 0i64;
+    let mut uses_label = false;
+    for (sign, term) in terms {
+        let value = if let Some(address) = symbols.labels.get(term.text) {
+            uses_label = true;
+            *address as i64
+        } else if let Some(value) = parse_immediate(&term) {
+            value
+        } else if symbols.externals.contains_key(term.text) {
+            // Left for the linker, see line_relocations
+            0
+        } else if is_name(&term) {
+            return Err(AssembleError::new(format!("undefined label {}", term)).span(term.range()));
+        } else {
+            return Err(AssembleError::new(format!("invalid immediate value {}", if term.is_empty() { text } else { term })).span(if term.is_empty() { text.range() } else { term.range() }));
+        };
+        total = total.checked_add(sign * value).ok_or_else(|| AssembleError::new(format!("expression {} overflows", text)).span(text.range()))?;
+    }
+
+    Ok((total, uses_label))
+}
+
+// Splits an expression into signed terms
+fn expression_terms(text: Token) -> Vec<(i64, Token)> {
+    let mut terms = Vec::new();
+    let mut sign = 1;
+    let mut term_start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, c) in text.char_indices() {
+        // '+' and '-' are characters, not operators
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '\'' {
+                quoted = false;
+            }
+            continue;
+        }
+        if c == '\'' {
+            quoted = true;
+        } else if (c == '+' || c == '-') && !text[term_start..index].trim().is_empty() {
+            // A sign at the start of a term is part of its number
+            terms.push((sign, text.slice(term_start..index).trim()));
+            sign = if c == '-' { -1 } else { 1 };
+            term_start = index + 1;
+        }
+    }
+    terms.push((sign, text.slice(term_start..text.len()).trim()));
+
+    terms
+}
+
+// Something that could be a label, as opposed to a number or a character
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+}
+
+// .extern sizes are in bytes
+fn extern_size(text: &str) -> Option<u32> {
+    parse_immediate(text).filter(|size| (0..=0x7fffffff).contains(size)).map(|size| size as u32)
+}
+
+// Which field of a line each .extern name it mentions ends up in. Only the forms a linker can patch are allowed: an
+// .extern in .byte, .half or a subtracted term has nowhere to go.
+fn line_relocations(asm_line: &str, address: u32, symbols: &Symbols) -> Result<Vec<Relocation>, AssembleError> {
+    if symbols.externals.is_empty() {
+        return Ok(Vec::new());
+    }
+    let parts = tokens(asm_line)?;
+    let parts = label_definition(&parts).1;
+    let Some(mnemonic) = parts.first().map(|mnemonic| mnemonic.to_ascii_lowercase()) else {
+        return Ok(Vec::new());
+    };
+    if mnemonic == ".extern" || mnemonic.starts_with(".glob") {
+        return Ok(Vec::new());
+    }
+
+    let mut relocations = Vec::new();
+    for (index, operand) in parts.iter().enumerate().skip(1) {
+        let expression = offset_base(*operand).map_or(*operand, |(offset, _)| offset);
+        for (sign, term) in expression_terms(expression) {
+            if !symbols.externals.contains_key(term.text) {
+                continue;
+            }
+            let mut relocation = |address: u32, kind: RelocationKind| {
+                relocations.push(Relocation { address, kind, symbol: term.to_string() })
+            };
+            match mnemonic.as_str() {
+                ".byte" | ".half" | ".ascii" | ".asciiz" | ".space" | ".org" =>
+                    return Err(AssembleError::new(format!(".extern {} can't be used in {}", term, parts[0])).span(term.range())),
+                _ if sign < 0 => return Err(AssembleError::new(format!(".extern {} can't be subtracted", term)).span(term.range())),
+                ".word" => relocation(address.wrapping_add(4 * (index as u32 - 1)), RelocationKind::Word),
+                "j" | "jal" => relocation(address, RelocationKind::Jump),
+                "la" | "li" => {
+                    relocation(address, RelocationKind::High);
+                    relocation(address.wrapping_add(4), RelocationKind::Low);
+                }
+                "lui" => relocation(address, RelocationKind::High),
+                // The branch comes after the slt
+                "blt" | "bgt" | "ble" | "bge" => relocation(address.wrapping_add(4), RelocationKind::Branch),
+                _ if BRANCH_INSTRUCTIONS.contains(&mnemonic.as_str()) => relocation(address, RelocationKind::Branch),
+                _ => relocation(address, RelocationKind::Immediate),
+            }
+        }
+    }
+
+    Ok(relocations)
+}
+
+// Bytes in memory order, zero padded to whole words that are written out with the bytes in that order
+fn pack_bytes(bytes: &[u8], big_endian: bool) -> Vec<u32> {
+    bytes.chunks(4).map(|chunk| {
+        let mut word = [0u8; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        if big_endian { u32::from_be_bytes(word) } else { u32::from_le_bytes(word) }
+    }).collect()
+}
+
+// The bytes of quoted strings, one after another, each followed by a null when terminated
+fn string_bytes(strings: &[Token], terminated: bool) -> Result<Vec<u8>, AssembleError> {
+    let mut bytes = Vec::new();
+
+    for string in strings {
+        let body = string.strip_prefix('"').ok_or_else(|| AssembleError::new(format!("expected a quoted string, found {}", string)).span(string.range()))?;
+        let mut chars = body.chars();
+        let mut closed = false;
+        while let Some(c) = chars.next() {
+            let c = match c {
+                '"' => {
+                    closed = true;
+                    break;
+                }
+                '\\' => match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '\'' | '"')) => c,
+                    _ => return Err(AssembleError::new(format!("invalid escape in string {}", string)).span(string.range())),
+                },
+                c => c,
+            };
+            let mut buffer = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+        }
+        if !closed {
+            return Err(AssembleError::new("unterminated string literal".to_owned()).span(string.range()));
+        }
+        if !chars.as_str().is_empty() {
+            return Err(AssembleError::new(format!("unexpected {} after string", chars.as_str())).span(string.slice(string.len() - chars.as_str().len()..string.len()).range()));
+        }
+        if terminated {
+            bytes.push(0);
+        }
+    }
+
+    Ok(bytes)
+}
+
+// The inside of a character literal: exactly one character or one escape
+fn char_literal(literal: &str) -> Option<i64> {
+    let mut chars = literal.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            _ => return None,
+        },
+        '\'' => return None,
+        c => c,
+    };
+
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(c as i64)
+}
+
+// 16-bit I type immediate, unsigned for the logical instructions and signed (stored as two's complement) for the rest
+fn immediate_16(text: Token, unsigned: bool, symbols: &Symbols) -> Result<u32, AssembleError> {
+    let (value, _) = evaluate(text, symbols)?;
+    let range = if unsigned { 0..=0xffff } else { -0x8000..=0x7fff };
+    if unsigned && (-0x8000..0).contains(&value) {
+        return Err(AssembleError::new(format!("immediate {} can't be negative because it is zero-extended, use 0x{:04x} for the same bits", text, value & 0xffff)).span(text.range()));
+    }
+    if !range.contains(&value) {
+        return Err(AssembleError::new(format!("immediate {} is out of range, expected {} to {}", text, range.start(), range.end())).span(text.range()));
+    }
+
+    Ok(value as u32 & 0xffff)
+}
+
+// andi, ori, xori and lui don't sign extend their immediate
+fn zero_extended(opcode: u32) -> bool {
+    matches!(opcode, ANDI_OPCODE | ORI_OPCODE | XORI_OPCODE | LUI_OPCODE)
+}
+
+// The target is a label (plus or minus a constant) or a literal offset, counted in words from the instruction after the branch
+fn branch_offset(target: Token, symbols: &Symbols, address: u32) -> Result<u32, AssembleError> {
+    let offset = match evaluate(target, symbols)? {
+        (target_address, true) => (target_address - (address as i64 + 4)) / 4,
+        (offset, false) => offset,
+    };
+
+    if offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+        return Err(AssembleError::new(format!("branch to {} is out of range, offset {} doesn't fit in 16 bits", target, offset)).span(target.range()));
+    }
+
+    Ok(offset as u32 & 0xffff)
+}
+
+// "op $s, target" for the branches that compare one register with zero. The rt field is fixed by the instruction.
+fn assemble_branch(opcode: u32, rt_code: u32, parts: Vec<Token>, symbols: &Symbols, address: u32) -> Result<u32, AssembleError> {
+    let s_register = register(parts[1])?;
+    let offset = branch_offset(parts[2], symbols, address)?;
+
+    Ok(offset | (rt_code << 16) | (s_register << 21) | (opcode << 26))
+}
+
+// "op $s, imm" traps compare with a sign extended immediate, even the unsigned ones
+fn assemble_trap_immediate(rt_code: u32, parts: Vec<Token>, symbols: &Symbols) -> Result<u32, AssembleError> {
+    let s_register = register(parts[1])?;
+    let immediate = immediate_16(parts[2], false, symbols)?;
+
+    Ok(immediate | (rt_code << 16) | (s_register << 21) | (REGIMM_OPCODE << 26))
+}
+
+fn disassemble_branch(instruction: u32, instruction_name: &str, address: u32, symbols: &HashMap<u32, String>) -> String {
+    let s_register = Register::from_field(instruction >> 21).name();
+    let offset = instruction as u16 as i16;
+    let target = address.wrapping_add(4).wrapping_add((offset as i32 * 4) as u32);
+
+    match symbols.get(&target) {
+        Some(label) => format!("{} {}, {}", instruction_name, s_register, label),
+        None => format!("{} {}, {}", instruction_name, s_register, offset),
+    }
+}
+
+fn disassemble_i(instruction: u32, instruction_name: &str, address: u32, symbols: &HashMap<u32, String>) -> String {
+    let t_register = Register::from_field(instruction >> 16).name();
+    let s_register = Register::from_field(instruction >> 21).name();
+    let immediate = instruction & 0xffff;
+
+    if offset_opcode(instruction >> 26) {
+        let t_register = match instruction >> 26 {
+            LWC1_OPCODE | SWC1_OPCODE => fpu_register_name(instruction >> 16),
+            _ => t_register.to_owned(),
+        };
+        // Sign extend the offset so 0xfffc prints as -4
+        format!("{} {}, {}({})", instruction_name, t_register, immediate as u16 as i16, s_register)
+    } else if instruction_name.eq("lui") {
+        format!("{} {}, {}", instruction_name, t_register, immediate)
+    } else if instruction_name.eq("blez") || instruction_name.eq("bgtz") {
+        disassemble_branch(instruction, instruction_name, address, symbols)
+    } else if instruction_name.eq("beq") || instruction_name.eq("bne") {
+        let offset = immediate as u16 as i16;
+        let target = address.wrapping_add(4).wrapping_add((offset as i32 * 4) as u32);
+        match symbols.get(&target) {
+            Some(label) => format!("{} {}, {}, {}", instruction_name, s_register, t_register, label),
+            None => format!("{} {}, {}, {}", instruction_name, s_register, t_register, offset),
+        }
+    } else if zero_extended(instruction >> 26) {
+        // Logical immediates are bit masks, so they read best in hex
+        format!("{} {}, {}, 0x{:x}", instruction_name, t_register, s_register, immediate)
+    } else {
+        format!("{} {}, {}, {}", instruction_name, t_register, s_register, immediate as u16 as i16)
+    }
+}
+
+fn assemble_r(func_code: u32, parts: Vec<Token>) -> Result<u32, AssembleError> {
+    if func_code == SYSCALL_INSTRUCTION {
+        return Ok(func_code);
+    }
+    // "break code" puts its code in the 20 bits between the opcode and func code
+    if func_code == BREAK_OPCODE {
+        let code = match parts.get(1) {
+            Some(code) => parse_immediate(code)
+                .filter(|code| (0..=0xfffff).contains(code))
+                .ok_or_else(|| AssembleError::new(format!("invalid break code {}, expected 0 to 1048575", code)).span(code.range()))? as u32,
+            None => 0,
+        };
+        return Ok(func_code | (code << 6));
+    }
+    // "jr $s", "mfhi $d" and "div $s, $t" leave the other register fields zero
+    if func_code == JR_OPCODE || func_code == MTHI_OPCODE || func_code == MTLO_OPCODE {
+        return Ok(func_code | (register(parts[1])? << 21));
+    }
+    // "jalr $d, $s" or "jalr $s", which links in $ra
+    if func_code == JALR_OPCODE {
+        let (d_register, s_register) = match parts.len() {
+            2 => (31, register(parts[1])?),
+            _ => (register(parts[1])?, register(parts[2])?),
+        };
+        return Ok(func_code | (d_register << 11) | (s_register << 21));
+    }
+    if func_code == MFHI_OPCODE || func_code == MFLO_OPCODE {
+        return Ok(func_code | (register(parts[1])? << 11));
+    }
+    // "teq $s, $t, code" keeps its code between the registers and the func code
+    if matches!(func_code, TGE_OPCODE | TGEU_OPCODE | TLT_OPCODE | TLTU_OPCODE | TEQ_OPCODE | TNE_OPCODE) {
+        let code = match parts.get(3) {
+            Some(code) => parse_immediate(code)
+                .filter(|code| (0..=0x3ff).contains(code))
+                .ok_or_else(|| AssembleError::new(format!("invalid trap code {}, expected 0 to 1023", code)).span(code.range()))? as u32,
+            None => 0,
+        };
+        return Ok(func_code | (code << 6) | (register(parts[2])? << 16) | (register(parts[1])? << 21));
+    }
+    if matches!(func_code, DIV_OPCODE | DIVU_OPCODE | MULT_OPCODE | MULTU_OPCODE) {
+        return Ok(func_code | (register(parts[2])? << 16) | (register(parts[1])? << 21));
+    }
+
+    let shift_opcode = func_code == SLL_OPCODE || func_code == SRL_OPCODE || func_code == SRA_OPCODE;
+    let variable_shift_opcode = func_code == SLLV_OPCODE || func_code == SRLV_OPCODE || func_code == SRAV_OPCODE;
+    let shift_amount = if shift_opcode {
+        let shift_amount = parse_immediate(&parts[3]).ok_or_else(|| AssembleError::new(format!("invalid shift amount {}", parts[3])).span(parts[3].range()))?;
+        if !(0..32).contains(&shift_amount) {
+            return Err(AssembleError::new(format!("shift amount {} is out of range, expected 0 to 31", parts[3])).span(parts[3].range()));
+        }
+        shift_amount as u32
+    } else {
+        0
+    };
+    let d_register = register(parts[1])?;
+    // "op $d, $s, $t" except for shifts, which take the shifted register ($t) first
+    let (s_index, t_index) = if shift_opcode || variable_shift_opcode { (3, 2) } else { (2, 3) };
+    let t_register = register(parts[t_index])?;
+    let s_register = if shift_opcode { 0 } else { register(parts[s_index])? };
+
+    // no need to specify opcode as it is always zero for R type instructions
+    Ok(func_code | (shift_amount << 6) | (d_register << 11) | (t_register << 16) | (s_register << 21))
+}
+
+fn disassemble_r(instruction: u32, instruction_name: &str) -> String {
+    let d_register = Register::from_field(instruction >> 11).name();
+    let t_register = Register::from_field(instruction >> 16).name();
+    let s_register = Register::from_field(instruction >> 21).name();
+
+    match instruction_name {
+        "syscall" => instruction_name.to_owned(),
+        "break" => match (instruction >> 6) & 0xfffff {
+            0 => instruction_name.to_owned(),
+            code => format!("{} {}", instruction_name, code),
+        },
+        "jr" | "mthi" | "mtlo" => format!("{} {}", instruction_name, s_register),
+        "mfhi" | "mflo" => format!("{} {}", instruction_name, d_register),
+        "jalr" => format!("{} {}, {}", instruction_name, d_register, s_register),
+        "sll" | "srl" | "sra" => format!("{} {}, {}, {}", instruction_name, d_register, t_register, (instruction >> 6) & 0b11111),
+        // Variable shifts take the shifted register first, like their constant forms
+        "sllv" | "srlv" | "srav" => format!("{} {}, {}, {}", instruction_name, d_register, t_register, s_register),
+        "div" | "divu" | "mult" | "multu" => format!("{} {}, {}", instruction_name, s_register, t_register),
+        "teq" | "tge" | "tgeu" | "tlt" | "tltu" | "tne" => match (instruction >> 6) & 0x3ff {
+            0 => format!("{} {}, {}", instruction_name, s_register, t_register),
+            code => format!("{} {}, {}, {}", instruction_name, s_register, t_register, code),
+        },
+        _ => format!("{} {}, {}, {}", instruction_name, d_register, s_register, t_register),
+    }
+}
+
+// The target is a label or a byte address, encoded as the low 26 bits of its word address
+// The target keeps only its low 28 bits, so it has to be in the same 256 MB region as the delay slot
+fn assemble_j(opcode: u32, target: Token, symbols: &Symbols, address: u32) -> Result<u32, AssembleError> {
+    let value = evaluate(target, symbols)?.0;
+    if value < 0 {
+        return Err(AssembleError::new(format!("jump target {} is negative", target)).span(target.range()));
+    }
+    let target_address = u32::try_from(value).map_err(|_| AssembleError::new(format!("jump target {} doesn't fit in 32 bits", target)).span(target.range()))?;
+    if target_address % 4 != 0 {
+        return Err(AssembleError::new(format!("jump target 0x{:08x} isn't word aligned", target_address)).span(target.range()));
+    }
+    // .extern targets are placed by the linker, which checks the region itself
+    let external = expression_terms(target).iter().any(|(_, term)| symbols.externals.contains_key(term.text));
+    if !external && (target_address ^ address.wrapping_add(4)) & 0xf0000000 != 0 {
+        return Err(AssembleError::new(format!("jump target 0x{:08x} is outside the 256 MB region of the jump at 0x{:08x}", target_address, address)).span(target.range()));
+    }
+
+    Ok(((target_address >> 2) & 0x03ffffff) | (opcode << 26))
+}
+
+// Prints the byte address the 26-bit word target points at
+fn disassemble_j(instruction: u32, instruction_name: &&str, address: u32, symbols: &HashMap<u32, String>) -> String {
+    // The top 4 bits come from the address of the delay slot
+    let target = (address.wrapping_add(4) & 0xf0000000) | ((instruction & 0x03ffffff) << 2);
+
+    match symbols.get(&target) {
+        Some(label) => format!("{} {}", instruction_name, label),
+        None => format!("{} 0x{:08x}", instruction_name, target),
+    }
+}
+
+// https://www.d.umn.edu/~gshute/mips/jtype.html
+fn create_j_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("j", 0b000010),
+        ("jal", 0b000011)
+    ])
+}
+
+// https://www.d.umn.edu/~gshute/mips/itype.html
+fn create_i_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("addi", 0b001000),
+        ("addiu", ADDIU_OPCODE),
+        ("andi", ANDI_OPCODE),
+        ("beq", BEQ_OPCODE),
+        ("bgtz", BGTZ_OPCODE),
+        ("blez", BLEZ_OPCODE),
+        ("bne", BNE_OPCODE),
+        ("lb", LB_OPCODE),
+        ("lbu", LBU_OPCODE),
+        ("lh", LH_OPCODE),
+        ("lhu", LHU_OPCODE),
+        ("ll", LL_OPCODE),
+        ("lui", LUI_OPCODE),
+        ("lw", LW_OPCODE),
+        ("lwc1", LWC1_OPCODE),
+        ("lwl", LWL_OPCODE),
+        ("lwr", LWR_OPCODE),
+        ("ori", ORI_OPCODE),
+        ("sb", SB_OPCODE),
+        ("sc", SC_OPCODE),
+        ("sh", SH_OPCODE),
+        ("slti", 0b001010),
+        ("sltiu", 0b001011),
+        ("sw", SW_OPCODE),
+        ("swc1", SWC1_OPCODE),
+        ("swl", SWL_OPCODE),
+        ("swr", SWR_OPCODE),
+        ("xori", XORI_OPCODE),
+    ])
+}
+
+// rt field codes of the REGIMM branches, whose opcode is always 1
+fn create_regimm_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("bgez", 0b00001),
+        ("bgezal", 0b10001),
+        ("bltz", 0b00000),
+        ("bltzal", 0b10000),
+        ("teqi", 0b01100),
+        ("tgei", TGEI_CODE),
+        ("tgeiu", 0b01001),
+        ("tlti", 0b01010),
+        ("tltiu", 0b01011),
+        ("tnei", TNEI_CODE),
+    ])
+}
+
+// Func codes of the FPU arithmetic instructions, before their ".s", ".d" or ".w" suffix
+fn create_fpu_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("abs", 0b000101),
+        ("add", 0b000000),
+        ("c.eq", 0b110010),
+        ("c.le", 0b111110),
+        ("c.lt", 0b111100),
+        ("cvt.d", 0b100001),
+        ("cvt.s", 0b100000),
+        ("cvt.w", 0b100100),
+        ("div", 0b000011),
+        ("mov", 0b000110),
+        ("mul", 0b000010),
+        ("neg", 0b000111),
+        ("sub", 0b000001),
+    ])
+}
+
+// Func codes of the MIPS32 instructions under the SPECIAL2 opcode
+fn create_special2_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("clo", CLO_OPCODE),
+        ("clz", CLZ_OPCODE),
+        ("madd", 0b000000),
+        ("maddu", 0b000001),
+        ("msub", 0b000100),
+        ("msubu", 0b000101),
+        ("mul", MUL_OPCODE),
+    ])
+}
+
+// Func codes. Opcode of R-type is always zero
+// https://www.d.umn.edu/~gshute/mips/rtype.html
+fn create_r_codes<'a>() -> BiMap<&'a str, u32> {
+    BiMap::from_iter([
+        ("add", 0b100000),
+        ("addu", ADDU_OPCODE),
+        ("and", 0b100100),
+        ("break", BREAK_OPCODE),
+        ("div", DIV_OPCODE),
+        ("divu", DIVU_OPCODE),
+        ("jalr", JALR_OPCODE),
+        ("jr", JR_OPCODE),
+        ("mfhi", MFHI_OPCODE),
+        ("mflo", MFLO_OPCODE),
+        ("mthi", MTHI_OPCODE),
+        ("mtlo", MTLO_OPCODE),
+        ("mult", MULT_OPCODE),
+        ("multu", MULTU_OPCODE),
+        ("nor", NOR_OPCODE),
+        ("or", 0b100101),
+        ("sll", SLL_OPCODE),
+        ("sllv", SLLV_OPCODE),
+        ("slt", SLT_OPCODE),
+        ("sltu", 0b101011),
+        ("sra", SRA_OPCODE),
+        ("srav", SRAV_OPCODE),
+        ("srl", SRL_OPCODE),
+        ("srlv", SRLV_OPCODE),
+        ("sub", SUB_OPCODE),
+        ("subu", 0b100011),
+        ("syscall", SYSCALL_INSTRUCTION),
+        ("teq", TEQ_OPCODE),
+        ("tge", TGE_OPCODE),
+        ("tgeu", TGEU_OPCODE),
+        ("tlt", TLT_OPCODE),
+        ("tltu", TLTU_OPCODE),
+        ("tne", TNE_OPCODE),
+        ("xor", 0b100110)
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble_str_uses_the_mars_text_base() {
+        // Same words the CLI gives without --base
+        assert_eq!(assemble_str("j end\nla $a0, end\nend: nop").unwrap(), vec![0x08100003, 0x3c010040, 0x3424000c, 0x00000000]);
+    }
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn extern_references_assemble_to_zero_with_relocations() {
+        let source = lines(".extern buffer 64\nla $a0, buffer\njal print\nlw $t0, buffer+4($gp)\n.extern print 0\n.data\n.word 1, buffer");
+        let program = Assembler::new().assemble_program(&source, &AssembleOptions::default()).unwrap();
+
+        assert_eq!(program.text, vec![0x3c010000, 0x34240000, 0x0c000000, 0x8f880004]);
+        assert_eq!(program.data, vec![1, 0]);
+        assert_eq!(program.symbols.externals, HashMap::from([("buffer".to_owned(), 64), ("print".to_owned(), 0)]));
+        let relocations: Vec<(u32, RelocationKind, &str)> = program.relocations.iter()
+            .map(|relocation| (relocation.address, relocation.kind, relocation.symbol.as_str()))
+            .collect();
+        assert_eq!(relocations, vec![
+            (0x00400000, RelocationKind::High, "buffer"),
+            (0x00400004, RelocationKind::Low, "buffer"),
+            (0x00400008, RelocationKind::Jump, "print"),
+            (0x0040000c, RelocationKind::Immediate, "buffer"),
+            (0x00400014, RelocationKind::Word, "buffer"),
+        ]);
+    }
+
+    #[test]
+    fn bad_extern_uses_are_reported() {
+        for (source, expected) in [
+            (".extern x 4\nx: nop", (1, "x is both a label and .extern")),
+            (".extern x 4\n.byte x", (2, ".extern x can't be used in .byte")),
+            (".extern x 4\nj 8-x", (2, ".extern x can't be subtracted")),
+        ] {
+            let errors = Assembler::new().assemble_lines(&lines(source), &AssembleOptions::default()).unwrap_err();
+            let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line_number, err.message.as_str())).collect();
+            assert_eq!(messages, vec![expected], "{}", source);
+        }
+    }
+
+    #[test]
+    fn jump_targets_must_be_reachable() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+        for (source, message, caret) in [
+            ("j -4", "jump target -4 is negative", (2, 2)),
+            ("j 0x1ffffffff", "jump target 0x1ffffffff doesn't fit in 32 bits", (2, 11)),
+            ("jal 0x00400002", "jump target 0x00400002 isn't word aligned", (4, 10)),
+            ("j 0x10000000", "jump target 0x10000000 is outside the 256 MB region of the jump at 0x00400000", (2, 10)),
+        ] {
+            let err = assembler.assemble_line(source, DEFAULT_TEXT_BASE, &AssembleOptions::default(), &symbols).unwrap_err().at(1, source);
+            assert_eq!((err.message.as_str(), err.column()), (message, Some(caret)), "{}", source);
+        }
+        // The region is the delay slot's, so the last word of one region already jumps within the next
+        assert_eq!(assembler.assemble_line("j 0x10000000", 0x0ffffffc, &AssembleOptions::default(), &symbols).unwrap(), vec![0x08000000]);
+    }
+
+    #[test]
+    fn oversized_images_are_rejected_before_they_are_built() {
+        for (source, expected) in [
+            (".space 0x3fffffff", (1, "the image would be 1073741823 bytes, the maximum is 16777216")),
+            ("nop\n.org 0x3fffffff\nnop", (2, "the image would be 1073741823 bytes, the maximum is 16777216")),
+        ] {
+            let errors = Assembler::new().assemble_lines(&lines(source), &AssembleOptions::default()).unwrap_err();
+            let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line_number, err.message.as_str())).collect();
+            assert_eq!(messages, vec![expected], "{}", source);
+        }
+
+        let options = AssembleOptions { max_image_size: 8, ..AssembleOptions::default() };
+        assert!(Assembler::new().assemble_lines(&lines(".data\n.word 1\n.space 4"), &options).is_ok());
+        let errors = Assembler::new().assemble_lines(&lines(".data\n.word 1\n.space 8"), &options).unwrap_err();
+        assert_eq!((errors[0].line_number, errors[0].column()), (3, Some((0, 6))));
+
+        // A line on its own, as the REPL assembles it
+        let err = Assembler::new().assemble_line(".space 0x3fffffff", 0, &AssembleOptions::default(), &Symbols::default()).unwrap_err();
+        assert_eq!(err.message, ".space 0x3fffffff is more than the maximum image size of 16777216 bytes");
+    }
+
+    #[test]
+    fn sections_must_end_by_the_top_of_memory() {
+        let at_top = |text_base: u32, data_base: Option<u32>| AssembleOptions { text_base, data_base, ..AssembleOptions::default() };
+        for (source, options, expected) in [
+            ("nop\nnop\nnop", at_top(0xfffffffc, None), (2, ".text runs past the top of the address space, from 0xfffffffc there is only room for 4 bytes")),
+            (".data\n.word 1\n.word 2", at_top(DEFAULT_TEXT_BASE, Some(0xfffffffc)), (3, ".data runs past the top of the address space, from 0xfffffffc there is only room for 4 bytes")),
+            // Data that follows the text has no room left at all
+            ("nop\n.data\n.word 1, ext\n.extern ext 4", at_top(0xfffffffc, None), (3, ".data runs past the top of the address space, from 0x100000000 there is only room for 0 bytes")),
+        ] {
+            let errors = Assembler::new().assemble_program(&lines(source), &options).unwrap_err();
+            let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line_number, err.message.as_str())).collect();
+            assert_eq!(messages, vec![expected], "{}", source);
+        }
+
+        // The last word of memory is still usable, relocations included
+        let program = Assembler::new().assemble_program(&lines(".extern ext 4\n.data\n.word ext"), &at_top(DEFAULT_TEXT_BASE, Some(0xfffffffc))).unwrap();
+        assert_eq!(program.relocations, vec![Relocation { address: 0xfffffffc, kind: RelocationKind::Word, symbol: "ext".to_owned() }]);
+    }
+
+    #[test]
+    fn data_tables_are_not_limited_to_a_few_tokens() {
+        let values: Vec<String> = (0..16).map(|value| value.to_string()).collect();
+        let source = vec![format!(".word {}", values.join(", ")), format!(".byte {}", values.join(", "))];
+        let words = Assembler::new().assemble_lines(&source, &AssembleOptions::default()).unwrap();
+
+        assert_eq!(words.len(), 20);
+        assert_eq!(words[15], 15);
+        assert_eq!(words[16], 0x03020100);
+    }
+
+    #[test]
+    fn runaway_lines_are_rejected() {
+        let tokens = vec!["1"; MAX_LINE_TOKENS + 1].join(" ");
+        assert_eq!(tokenize(&tokens).unwrap_err().message, format!("too many operands, at most {} tokens are allowed per line", MAX_LINE_TOKENS));
+
+        // Reported once, by the layout
+        let options = AssembleOptions { max_line_len: 8, ..AssembleOptions::default() };
+        let errors = Assembler::new().assemble_lines(&lines("nop\n.word 1, 2, 3"), &options).unwrap_err();
+        let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line_number, err.message.as_str())).collect();
+        assert_eq!(messages, vec![(2, "line is 13 bytes long, the maximum is 8")]);
+    }
+
+    #[test]
+    fn add_exit_ends_the_text_and_moves_the_data() {
+        let options = AssembleOptions { add_exit: true, ..AssembleOptions::default() };
+        let program = Assembler::new().assemble_program(&lines("la $a0, message\n.data\nmessage: .word 7"), &options).unwrap();
+
+        assert_eq!(program.text, vec![0x3c010040, 0x34240010, 0x2402000a, SYSCALL_INSTRUCTION]);
+        assert_eq!(program.data_base, 0x00400010);
+        assert_eq!(program.symbols.labels["message"], 0x00400010);
+        assert_eq!(program.data, vec![7]);
+
+        // Nothing is added when the exit is already there
+        let program = Assembler::new().assemble_program(&lines("li $v0, 10\nsyscall\n.data\nmessage: .word 7"), &options).unwrap();
+        assert_eq!(program.words(), vec![0x2402000a, SYSCALL_INSTRUCTION, 7]);
+        assert_eq!(program.symbols.labels["message"], 0x00400008);
+    }
+
+    #[test]
+    fn main_first_puts_main_at_the_text_base() {
+        let options = AssembleOptions { main_first: true, ..AssembleOptions::default() };
+        let source = lines(".globl main\nhelper: jr $ra\nnop\nmain: jal helper\nj main\n.data\nvalue: .word helper");
+        let program = Assembler::new().assemble_program(&source, &options).unwrap();
+
+        assert_eq!(program.symbols.labels["main"], options.text_base);
+        assert_eq!(program.symbols.labels["helper"], 0x00400008);
+        assert_eq!(program.words(), vec![0x0c100002, 0x08100000, 0x03e00008, NOP_INSTRUCTION, 0x00400008]);
+    }
+
+    #[test]
+    fn main_first_refuses_to_move_code_that_falls_into_main() {
+        let options = AssembleOptions { main_first: true, ..AssembleOptions::default() };
+        let errors = Assembler::new().assemble_lines(&lines(".globl main\naddi $t0, $t0, 1\nmain: nop"), &options).unwrap_err();
+
+        assert_eq!(errors[0].line_number, 2);
+        assert_eq!(errors[0].message, "the code before main falls through into it, end it with a jump to move main first");
+    }
+
+    #[test]
+    fn read_mhc_returns_the_header_base() {
+        let words = [NOP_INSTRUCTION, 0x08000400];
+        let mut bytes = mhc_header(words.len(), true, 0x00001000);
+        bytes.extend(mhc_bytes(&words, true));
+
+        assert_eq!(read_mhc(&bytes, false), Ok((words.to_vec(), Some(0x00001000))));
+        assert_eq!(read_mhc(&mhc_bytes(&words, false), false), Ok((words.to_vec(), None)));
+    }
+
+    #[test]
+    fn carets_point_at_the_operand_at_fault() {
+        let assembler = Assembler::new();
+        let strict = AssembleOptions::default();
+        let lenient = AssembleOptions { lenient_registers: true, ..AssembleOptions::default() };
+        for (source, options, column) in [
+            ("addi $t0, $t0, $t0", &strict, (15, 3)),
+            ("  addi t0, t0, t0", &lenient, (13, 2)),
+            ("lw t0, 4(t1", &lenient, (7, 4)),
+            // Parts of an operand, and operands of expansions
+            ("li $t0, nope + 4", &strict, (8, 4)),
+            ("lw $t0, nope($t0)", &strict, (8, 4)),
+            ("blt $t0, $t1, nope", &strict, (14, 4)),
+            ("lw t0, 4(t0 + 1)", &lenient, (9, 6)),
+            (".data\n.asciiz \"a\" \"b\"x", &strict, (15, 1)),
+        ] {
+            let errors = assembler.assemble_lines(&lines(source), options).unwrap_err();
+            assert_eq!(errors[0].column(), Some(column), "{}", source);
+        }
+    }
+
+    #[test]
+    fn byte_data_is_contiguous_and_words_are_aligned() {
+        let source = lines(".data\na: .byte 1\nb: .byte 2\nc: .half 3\nd: .asciiz \"hi\"\ne: .word 4\nf: .space 1\ng: .byte 5");
+        let program = Assembler::new().assemble_program(&source, &AssembleOptions::default()).unwrap();
+        let labels = &program.symbols.labels;
+        let offsets: Vec<u32> = ["a", "b", "c", "d", "e", "f", "g"].iter().map(|label| labels[*label] - program.data_base).collect();
+
+        assert_eq!(offsets, vec![0, 1, 2, 4, 8, 12, 13]);
+        assert_eq!(program.data, vec![0x00030201, 0x00006968, 4, 0x00000500]);
+    }
+
+    #[test]
+    fn ihex_switches_the_upper_address_for_a_separate_data_base() {
+        let text = [0x3c011001, 0x34240000];
+        let hex = ihex_string(&[(0x00400000, &text), (0x10010000, &[7])], false);
+
+        assert_eq!(hex, ":020000040040BA\n:080000000110013C0000243452\n:020000041001E9\n:0400000007000000F5\n:00000001FF\n");
+    }
+
+    #[test]
+    fn section_free_programs_are_all_text_at_the_text_base() {
+        let source = lines("start: addi $t0, $zero, 1\nloop: addi $t0, $t0, 1\nbne $t0, $zero, loop\nj start");
+        let options = AssembleOptions::default();
+
+        assert_eq!(line_addresses(&source, &options), vec![0x00400000, 0x00400004, 0x00400008, 0x0040000c]);
+        let program = Assembler::new().assemble_program(&source, &options).unwrap();
+        assert_eq!(program.text, vec![0x20080001, 0x21080001, 0x1500fffe, 0x08100000]);
+        assert!(program.data.is_empty());
+        assert_eq!(program.symbols.labels["loop"], 0x00400004);
+    }
+
+    #[test]
+    fn float_constants_load_their_ieee_bits_through_at() {
+        let assembler = Assembler::new();
+        let options = AssembleOptions { extensions: true, ..AssembleOptions::default() };
+        let symbols = Symbols::default();
+
+        // 1.0 is 0x3f800000
+        assert_eq!(assembler.assemble_line("li.s $f0, 1.0", 0, &options, &symbols).unwrap(), vec![0x3c013f80, 0x34210000, 0x44810000]);
+        // -2.5 is 0xc004000000000000, the low word goes in the even register
+        assert_eq!(assembler.assemble_line("li.d $f2, -2.5", 0, &options, &symbols).unwrap(), vec![0x3c010000, 0x34210000, 0x44811000, 0x3c01c004, 0x34210000, 0x44811800]);
+        assert_eq!(assembler.assemble_line("li.s $f0, 1.0", 0, &AssembleOptions::default(), &symbols).unwrap_err().message, "unknown instruction li.s");
+        assert!(assembler.assemble_line("li.s $f0, one", 0, &options, &symbols).is_err());
+    }
+
+    #[test]
+    fn comments_after_directives_are_dropped() {
+        let assembler = Assembler::new();
+        let options = AssembleOptions::default();
+        let symbols = Symbols::default();
+
+        assert_eq!(tokenize(".word 5  # count").unwrap(), vec![".word", "5"]);
+        assert_eq!(assembler.assemble_line(".word 5  # count", 0, &options, &symbols).unwrap(), vec![5]);
+        assert_eq!(tokenize(".asciiz \"hi\" # greeting").unwrap(), vec![".asciiz", "\"hi\""]);
+        assert_eq!(assembler.assemble_line(".asciiz \"hi\" # greeting", 0, &options, &symbols).unwrap(), vec![0x00006968]);
+        // Inside the string they are just characters
+        assert_eq!(assembler.assemble_line(".ascii \"#1;\" ; note", 0, &options, &symbols).unwrap(), vec![0x003b3123]);
+    }
+
+    #[test]
+    fn operand_counts_are_checked_before_assembling() {
+        let errors = Assembler::new().assemble_lines(&lines("nop\nadd $t0, $t1\nsll $t0, $t1, 2, 3\njr"), &AssembleOptions::default()).unwrap_err();
+        let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line_number, err.message.as_str())).collect();
+
+        assert_eq!(messages, vec![
+            (2, "add expects 3 operands, found 2"),
+            (3, "sll expects 3 operands, found 4"),
+            (4, "jr expects 1 operand, found 0"),
+        ]);
+    }
+
+    #[test]
+    fn negative_load_offsets_round_trip_as_decimal() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+
+        assert_eq!(assembler.disassemble_word(0x8fbffffc).unwrap(), "lw $ra, -4($sp)");
+        assert_eq!(assembler.assemble_line("lw $ra, -4($sp)", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x8fbffffc]);
+        assert_eq!(assembler.disassemble_word(0xa3a8ff80).unwrap(), "sb $t0, -128($sp)");
+        // Other immediates keep their sign rules: ori zero extends
+        assert_eq!(assembler.disassemble_word(0x3508fffc).unwrap(), "ori $t0, $t0, 0xfffc");
+    }
+
+    #[test]
+    fn jumps_disassemble_to_their_target() {
+        let assembler = Assembler::new();
+
+        assert_eq!(assembler.disassemble_word(0x08100000).unwrap(), "j 0x00400000");
+        assert_eq!(assembler.disassemble_word(0x0c100003).unwrap(), "jal 0x0040000c");
+        // The top 4 bits come from the jump's own address
+        assert_eq!(assembler.disassemble_with_symbols(0x08000001, 0x10000000, &HashMap::new()).unwrap(), "j 0x10000004");
+        let symbols = HashMap::from([(0x0040000c, "print".to_owned())]);
+        assert_eq!(assembler.disassemble_with_symbols(0x0c100003, 0x00400000, &symbols).unwrap(), "jal print");
+    }
+
+    #[test]
+    fn byte_and_half_loads_and_stores_round_trip() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+        for (line, word) in [
+            ("lb $t0, 4($sp)", 0x83a80004),
+            ("lbu $t0, 4($sp)", 0x93a80004),
+            ("lh $t0, 4($sp)", 0x87a80004),
+            ("lhu $t0, 4($sp)", 0x97a80004),
+            ("sb $t0, 4($sp)", 0xa3a80004),
+            ("sh $t0, 4($sp)", 0xa7a80004),
+        ] {
+            assert_eq!(assembler.assemble_line(line, 0, &AssembleOptions::default(), &symbols).unwrap(), vec![word], "{}", line);
+            assert_eq!(assembler.disassemble_word(word).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn shifts_round_trip_with_their_shift_amount() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+        for (line, word) in [
+            ("sll $t0, $t1, 2", 0x00094080),
+            ("srl $t0, $t1, 31", 0x000947c2),
+            ("sra $t0, $t1, 4", 0x00094103),
+            // rd, rt, rs: the value in $t1 is shifted by $t2
+            ("sllv $t0, $t1, $t2", 0x01494004),
+            ("srlv $t0, $t1, $t2", 0x01494006),
+            ("srav $t0, $t1, $t2", 0x01494007),
+        ] {
+            assert_eq!(assembler.assemble_line(line, 0, &AssembleOptions::default(), &symbols).unwrap(), vec![word], "{}", line);
+            assert_eq!(assembler.disassemble_word(word).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn tabs_and_indentation_separate_tokens() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+
+        assert_eq!(tokenize("\tadd\t$t0,\t$t1 ,  $t2").unwrap(), vec!["add", "$t0", "$t1", "$t2"]);
+        assert_eq!(assembler.assemble_line("\tlw\t$t0,4($sp)", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x8fa80004]);
+        assert_eq!(assembler.assemble_line("    addi $s0\t$s0\t-1", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x2210ffff]);
+    }
+
+    #[test]
+    fn register_names_are_case_insensitive() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+
+        assert_eq!(assembler.assemble_line("ADD $T0, $a0, $ZERO", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x00804020]);
+        assert_eq!(assembler.assemble_line("lw $T0, 4($SP)", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x8fa80004]);
+        // Disassembly stays lowercase
+        assert_eq!(assembler.disassemble_word(0x00804020).unwrap(), "add $t0, $a0, $zero");
+    }
+
+    #[test]
+    fn hash_and_semicolon_both_start_comments() {
+        let assembler = Assembler::new();
+        let symbols = Symbols::default();
+        for line in ["addi $t0, $t0, 1 # bump", "addi $t0, $t0, 1 ; bump", "addi $t0, $t0, 1;# both", "addi $t0, $t0, 1#; both"] {
+            assert_eq!(assembler.assemble_line(line, 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x21080001], "{}", line);
+        }
+        assert!(tokenize("; only a comment").unwrap().is_empty());
+        // Not inside a character
+        assert_eq!(assembler.assemble_line("addi $t0, $zero, ';' ; semicolon", 0, &AssembleOptions::default(), &symbols).unwrap(), vec![0x2008003b]);
+    }
+
+    // Encodings as MARS 4.5 assembles them
+    #[test]
+    fn encodings_match_mars() {
+        let assembler = Assembler::new();
+        let options = AssembleOptions::default();
+        let symbols = Symbols::default();
+        for (line, word) in [
+            ("add $t0, $t1, $t2", 0x012a4020),
+            ("lw $t0, 4($sp)", 0x8fa80004),
+            ("addi $s0, $s0, -1", 0x2210ffff),
+            ("sll $t0, $t1, 2", 0x00094080),
+        ] {
+            assert_eq!(assembler.assemble_line(line, options.text_base, &options, &symbols).unwrap(), vec![word], "{}", line);
+        }
+    }
+}
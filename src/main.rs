@@ -1,29 +1,146 @@
 // Git repository available on GitHub at https://github.com/thedarkcolour/mips-assembler
 
-use std::ascii::AsciiExt;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Write};
-use bimap::BiMap;
-use clap::{Parser, ValueEnum};
-
-// Offset I type instructions
-const LW_OPCODE: u32 = 0b100011;
-const SW_OPCODE: u32 = 0b101011;
-
-// shamt R type instructions
-const SLL_OPCODE: u32 = 0b000000;
-const SLLV_OPCODE: u32 = 0b000100;
-const SRL_OPCODE: u32 = 0b000010;
-const SRLV_OPCODE: u32 = 0b000110;
-const SRA_OPCODE: u32 = 0b000011;
-const SRAV_OPCODE: u32 = 0b000111;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use mips_assembler::{collect_symbols, crc32, ihex_string, line_addresses, mhc_bytes, mhc_header, parse_hex_word, read_bin, read_hex, read_mhc, tokenize, tokenize_spans, words_from_bytes, Architecture, AssembleError, AssembleOptions, Assembler, InstructionType, Program, Symbols, DEFAULT_TEXT_BASE};
+
+// Stops include cycles from recursing forever
+const MAX_INCLUDE_DEPTH: usize = 16;
+// Column width of mnemonics with --pretty, enough for syscall
+const MNEMONIC_WIDTH: usize = 7;
 
 #[derive(Parser)]
 struct Args {
+    // Several source files are assembled as one program, in order
+    #[arg(short, long, num_args = 1.., required_unless_present_any = ["decode", "fields", "repl"])]
+    input_file: Vec<String>,
+    // Deprecated, use --direction and --format. "bin" and "mhc" disassemble that format.
+    #[arg(short, long, hide = true, conflicts_with = "direction")]
+    mode: Option<AssemblerMode>,
+    #[arg(short, long, value_enum)]
+    direction: Option<Direction>,
+    // Base name for the .bin/.mhc files when assembling, or the file to write when disassembling
     #[arg(short, long)]
-    input_file: String,
+    output: Option<String>,
+    // Print assembled words to stdout as hex instead of writing files (default when stdout is piped)
+    #[arg(long, conflicts_with = "files")]
+    stdout: bool,
+    // Write .bin and .mhc files even when stdout is piped
+    #[arg(long)]
+    files: bool,
+    // Prefix each printed word with its zero-based instruction index. Verilog memory files get the same index as a
+    // trailing "// N" comment, since $readmemh can't skip a prefix.
+    #[arg(long)]
+    number_words: bool,
+    // Disassemble the given hex words instead of reading a file
+    #[arg(long, num_args = 1.., value_name = "WORD")]
+    decode: Vec<String>,
+    // Print the CRC32 of the .mhc file's bytes to stderr, header included unless --headerless
+    #[arg(long)]
+    checksum: bool,
+    // Accept pseudo-instructions that aren't part of the standard set (li.s, li.d)
+    #[arg(long)]
+    extensions: bool,
+    // Instruction set revision to assemble and disassemble. mips32 adds mul, madd, msub and clz/clo, mips32r2 also adds
+    // ext, ins, seb, seh, wsbh, rotr and rotrv.
+    #[arg(long, value_enum, default_value_t = March::Mips1)]
+    march: March,
+    // Reject source lines longer than this many bytes
+    #[arg(long, default_value_t = 1024)]
+    max_line_len: usize,
+    // Reject programs whose text and data together would be more than this many bytes (16 MiB by default)
+    #[arg(long, default_value_t = 0x01000000)]
+    max_image_size: usize,
+    // Print the encoded fields of a single hex word
+    #[arg(long, value_name = "WORD")]
+    fields: Option<String>,
+    // End the text with an exit syscall unless it already ends with one, moving the data after it
+    #[arg(long, conflicts_with = "emit_test")]
+    add_exit: bool,
+    // Put the block starting at a ".globl main" label first in the text, so it lands on the text base
+    #[arg(long, conflicts_with = "emit_test")]
+    main_first: bool,
+    // Color disassembly output (auto only colors when writing to a terminal)
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    // Write and read .mhc files as bare words, without the container header
+    #[arg(long)]
+    headerless: bool,
+    // Extra directories searched by .include after the including file's own directory
+    #[arg(short = 'I', value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+    // Print Rust assert_eq! lines checking each source line's encoding, for building regression tests
+    #[arg(long)]
+    emit_test: bool,
+    // What to write when assembling (default .bin and .mhc) or what to read when disassembling (default mhc)
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    // Byte order of the words in .mhc files, both written and read
+    #[arg(long, value_enum, default_value_t = Endian::Little)]
+    endian: Endian,
+    // Print the tokens of each source line and the binary of each assembled word
     #[arg(short, long)]
-    mode: Option<AssemblerMode>,
+    verbose: bool,
+    // Prefix each disassembled line with its byte address
+    #[arg(long)]
+    show_addresses: bool,
+    // Byte address of the first word, decimal or 0x hex. The default is where MARS puts .text, or when disassembling
+    // a .mhc file, the base in its header.
+    #[arg(long, visible_alias = "text-base", value_parser = parse_address)]
+    base: Option<u32>,
+    // Byte address of the first .data word, such as MARS' 0x10010000. By default the data follows the text.
+    // Either way the data comes right after the text in the written image.
+    #[arg(long, value_parser = parse_address)]
+    data_base: Option<u32>,
+    // Check that disassembling the assembled words and assembling that again gives the same words
+    #[arg(long)]
+    verify: bool,
+    // Print instruction counts by type and the image size to stderr after assembling
+    #[arg(long)]
+    stats: bool,
+    // Symbol table file: written when assembling, read when disassembling to print labels instead of addresses
+    #[arg(long, value_name = "PATH")]
+    symbols: Option<PathBuf>,
+    // Relocation file written when assembling: "0xADDR TYPE name" for each field left as 0 by an .extern name
+    #[arg(long, value_name = "PATH")]
+    relocations: Option<PathBuf>,
+    // Print the words read when disassembling as indexed hex instead of disassembling them
+    #[arg(long)]
+    dump_hex: bool,
+    // Stop at the first word that isn't a known instruction instead of writing it as .word
+    #[arg(long)]
+    strict: bool,
+    // Space the bits in the .bin file by instruction field (opcode, registers, immediate...)
+    #[arg(long)]
+    annotate_bin: bool,
+    // Pad disassembled mnemonics so the operands line up in a column
+    #[arg(long)]
+    pretty: bool,
+    // Accept register names without their $, as in "add t0, t1, t2"
+    #[arg(long)]
+    lenient_registers: bool,
+    // Skip the .bin file when writing files. The .mhc still has its header, add --headerless for only the words.
+    #[arg(long, conflicts_with = "no_mhc")]
+    no_bin: bool,
+    // Skip the .mhc file when writing files
+    #[arg(long)]
+    no_mhc: bool,
+    // Read instructions or hex words from stdin one line at a time and print their encoding or disassembly
+    #[arg(long)]
+    repl: bool,
+    // Append zero words until the image is this many words long, for fixed-size ROM and RAM images
+    #[arg(long, value_name = "WORDS")]
+    pad_to: Option<usize>,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, ValueEnum)]
+enum Direction {
+    Assemble,
+    Disassemble,
 }
 
 #[derive(Eq, PartialEq, Clone, ValueEnum)]
@@ -36,266 +153,702 @@ enum AssemblerMode {
     Mhc,
 }
 
-fn main() {
-    let j_codes = create_j_codes();
-    let i_codes = create_i_codes();
-    let r_codes = create_r_codes();
-    let registers = create_register_codes();
+#[derive(Eq, PartialEq, Clone, Copy, ValueEnum)]
+enum Format {
+    // .bin and .mhc files
+    Files,
+    // ASCII binary, one word per line
+    Bin,
+    // Machine code bytes, with the container header unless --headerless
+    Mhc,
+    // Hex words, one per line, on stdout when assembling
+    Hex,
+    // .mem file of hex words for $readmemh
+    Verilog,
+    // .mem file of binary words for $readmemb
+    VerilogBin,
+    // Logisim "v2.0 raw" ROM image
+    Logisim,
+    // Intel HEX records at the base address
+    Ihex,
+    // "0xADDR 0xWORD" lines, like a MARS memory dump
+    Mars,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, ValueEnum)]
+enum Endian {
+    Little,
+    Big,
+}
 
+#[derive(Eq, PartialEq, Clone, Copy, ValueEnum)]
+enum March {
+    Mips1,
+    Mips32,
+    Mips32r2,
+}
+
+impl March {
+    fn architecture(self) -> Architecture {
+        match self {
+            March::Mips1 => Architecture::Mips1,
+            March::Mips32 => Architecture::Mips32,
+            March::Mips32r2 => Architecture::Mips32r2,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+fn main() {
     let args = Args::parse();
-    let input_path = &args.input_file;
-    let mode = args.mode.unwrap_or(AssemblerMode::Assemble);
+    let assembler = Assembler::with_architecture(args.march.architecture());
+    let color = args.color == ColorChoice::Always || (args.color == ColorChoice::Auto && std::io::stdout().is_terminal());
 
-    if mode == AssemblerMode::Assemble {
-        let binary_path = input_path.to_owned() + ".bin";
-        let mhc_path = input_path.to_owned() + ".mhc";
+    if !args.decode.is_empty() {
+        decode_words(&assembler, &args.decode, color, args.pretty);
+        return;
+    }
+    if args.repl {
+        repl(&assembler, &args, color);
+        return;
+    }
+    if let Some(word) = &args.fields {
+        match parse_hex_word(word) {
+            Ok(instruction) => println!("{}", assembler.format_fields(instruction)),
+            Err(err) => eprintln!("Invalid hex word {:?}: {}", word, err),
+        }
+        return;
+    }
 
-        assemble_file(&j_codes, &i_codes, &r_codes, &registers, &binary_path, input_path, &mhc_path);
-    } else {
-        let mut input_file = File::create(input_path).expect("No such file");
-        let mut instructions: Vec<u32> = Vec::new();
-        let mut bytes: Vec<u8>;
+    let input_paths = &args.input_file;
+    let input_path = &input_paths[0];
+    let big_endian = args.endian == Endian::Big;
+    let (direction, format) = direction_and_format(&args);
+    if direction == Direction::Disassemble && input_paths.len() > 1 {
+        Args::command().error(ErrorKind::TooManyValues, "disassembly takes a single input file").exit();
+    }
 
-        // Different reading modes
-        if mode == AssemblerMode::Bin {
-            let mut input_file = std::io::BufReader::new(input_file);
-            let mut s = String::new();
-
-            bytes = input_file.read_to_string(&mut s)
-                .expect("Failed to read")
-                .to_le_bytes()
-                .to_vec();
-        } else {
-            // copied from std::fs::read
-            let size = input_file.metadata().map(|m| m.len() as usize).ok();
-            bytes = Vec::with_capacity(size.unwrap_or(0));
-            input_file.read_to_end(&mut bytes).unwrap();
+    if args.emit_test {
+        emit_test(&assembler, input_paths, &args);
+    } else if args.verify {
+        // Data is whatever the source said it is, so only the text has to disassemble
+        let text = assemble_file(&assembler, input_paths, &args).unwrap_or_else(|errors| exit_with_errors(&errors)).text;
+        let options = assemble_options(&args);
+
+        if !verify_round_trip(&assembler, &text, &options) {
+            std::process::exit(1);
+        }
+        eprintln!("Round trip verified for {} text word{}", text.len(), if text.len() == 1 { "" } else { "s" });
+    } else if direction == Direction::Assemble {
+        let program = match assemble_file(&assembler, input_paths, &args) {
+            Ok(program) => program,
+            Err(errors) => exit_with_errors(&errors),
+        };
+        if let Some(symbols_path) = &args.symbols {
+            write_symbols(&program.symbols.labels, symbols_path);
+        }
+        if let Some(relocations_path) = &args.relocations {
+            write_relocations(&program, relocations_path);
+        }
+        let mut words = program.words();
+
+        if let Some(pad_to) = args.pad_to {
+            if words.len() > pad_to {
+                eprintln!("The program is {} words, which doesn't fit in --pad-to {}", words.len(), pad_to);
+                std::process::exit(1);
+            }
+            words.resize(pad_to, 0);
+        }
+
+        if args.stats {
+            print_stats(&assembler, &program.text, words.len());
         }
 
-        instructions.reserve(bytes.len() / 4);
-        for chunk in bytes.chunks(4) {
-            // Rust wants things in sized slices apparently
-            let mut chunk_4 = [0u8; 4];
-            chunk_4.copy_from_slice(chunk);
-            instructions.push(u32::from_le_bytes(chunk_4));
+        if args.checksum {
+            eprintln!("CRC32: {:08x}", crc32(&mhc_file_bytes(&words, &args)));
         }
+        // Where each part of the image goes, for the formats that record addresses. Padding extends the last part.
+        let text_len = if program.data.is_empty() { words.len() } else { program.text.len() };
+        let segments = [(program.text_base, &words[..text_len]), (program.data_base, &words[text_len..])];
+
+        // Piping into another program gets hex words, a terminal, an explicit output name or format gets files.
+        // Source from stdin has no name to base the files on.
+        let unnamed = args.output.is_none() && (input_path == "-" || (args.format.is_none() && !std::io::stdout().is_terminal()));
+        if args.stdout || format == Format::Hex || (!args.files && unnamed) {
+            write_hex(&words, args.number_words);
+        } else {
+            let base_path = args.output.as_ref().unwrap_or(input_path);
 
-        for instruction in instructions {
-            let opcode = instruction >> 26;
+            let binary_path = base_path.to_owned() + ".bin";
+            let mhc_path = base_path.to_owned() + ".mhc";
 
-            let result = if opcode == 0 {
-                disassemble_r(instruction, &registers, *r_codes.get_by_right(&(instruction & 0b111111)).unwrap())
-            } else {
-                if let Some(j_instruction) = j_codes.get_by_right(&opcode) {
-                    disassemble_j(instruction, j_instruction)
-                } else if let Some(i_instruction) = i_codes.get_by_right(&opcode) {
-                    disassemble_i(instruction, &registers, *i_instruction)
-                } else {
-                    panic!("Invalid opcode");
+            match format {
+                Format::Files => {
+                    let binary_path = (!args.no_bin).then_some(binary_path.as_str());
+                    let mhc_path = (!args.no_mhc).then_some(mhc_path.as_str());
+                    write_files(&assembler, &words, binary_path, mhc_path, &args);
                 }
+                Format::Bin => write_files(&assembler, &words, Some(&binary_path), None, &args),
+                Format::Mhc => write_files(&assembler, &words, None, Some(&mhc_path), &args),
+                Format::Hex => unreachable!("hex words are written to stdout"),
+                Format::Verilog | Format::VerilogBin => {
+                    write_verilog(&words, &(base_path.to_owned() + ".mem"), format == Format::VerilogBin, args.number_words);
+                }
+                Format::Logisim => write_logisim(&words, &(base_path.to_owned() + ".img")),
+                Format::Mars => write_mars(&segments, &(base_path.to_owned() + ".txt")),
+                Format::Ihex => {
+                    std::fs::write(base_path.to_owned() + ".hex", ihex_string(&segments, big_endian)).expect("Failed to write Intel HEX file");
+                }
+            }
+        }
+    } else {
+        // Different reading modes
+        let (instructions, header_base) = if format == Format::Bin || format == Format::Hex {
+            let text = read_text(Path::new(input_path));
+
+            match if format == Format::Bin { read_bin(&text) } else { read_hex(&text) } {
+                Ok(instructions) => (instructions, None),
+                Err(errors) => exit_with_errors(&errors),
+            }
+        } else {
+            let bytes = read_input(Path::new(input_path));
+
+            let instructions = if args.headerless {
+                (words_from_bytes(&bytes, big_endian), None)
+            } else {
+                read_mhc(&bytes, big_endian).unwrap_or_else(|err| {
+                    eprintln!("Invalid MHC file: {}", err);
+                    std::process::exit(1);
+                })
             };
+            // Only headerless words can be cut short, a header fixes the length
+            if !bytes.len().is_multiple_of(4) {
+                eprintln!("Warning: the last {} bytes, from offset 0x{:x}, don't make a whole word and were padded with zeros", bytes.len() % 4, bytes.len() - bytes.len() % 4);
+            }
+            instructions
+        };
+
+        // For checking byte order, no decoding
+        if args.dump_hex {
+            for (index, instruction) in instructions.iter().enumerate() {
+                println!("{}: 0x{:08x}", index, instruction);
+            }
+            return;
+        }
 
-            println!("{}", result);
+        let mut output: Box<dyn Write> = match &args.output {
+            Some(output_path) => Box::new(std::io::BufWriter::new(File::create(output_path).expect("Failed to create output file"))),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        // auto only colors a terminal, never a file
+        let color = color && (args.output.is_none() || args.color == ColorChoice::Always);
+
+        let symbols = args.symbols.as_ref().map(|symbols_path| read_symbols(symbols_path)).unwrap_or_default();
+        let base = args.base.or(header_base).unwrap_or(DEFAULT_TEXT_BASE);
+
+        for (index, instruction) in instructions.into_iter().enumerate() {
+            let address = base.wrapping_add(index as u32 * 4);
+            // Corrupt words or data become .word so the rest of the file still disassembles
+            let result = assembler.disassemble_with_symbols(instruction, address, &symbols).unwrap_or_else(|err| {
+                if args.strict {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+                format!(".word 0x{:08x}", instruction)
+            });
+            if args.show_addresses {
+                write!(output, "0x{:08x}: ", address).expect("Failed to write disassembly");
+            }
+            let result = if args.pretty { align_operands(&result) } else { result };
+            writeln!(output, "{}", if color { assembler.colorize(instruction, &result) } else { result }).expect("Failed to write disassembly");
         }
     }
 }
 
-fn assemble_file(j_codes: &BiMap<&str, u32>, i_codes: &BiMap<&str, u32>, r_codes: &BiMap<&str, u32>, registers: &BiMap<&str, u32>, binary_path: &str, asm_path: &str, mhc_path: &str) {
-    let result = std::fs::read_to_string(asm_path).expect("No such file");
-    let binary_file = File::create(binary_path).expect("Failed to create binary file");
-    let mhc_file = File::create(mhc_path).expect("MHC ");
-    // Buffered writers flush when they go out of scope
-    let mut binary_file = std::io::BufWriter::new(binary_file);
-    let mut mhc_file = std::io::BufWriter::new(mhc_file);
+// One line at a time: hex words ("0x..." or 8 hex digits) are disassembled, anything else is assembled at the base
+// address. Errors are printed and the session carries on.
+fn repl(assembler: &Assembler, args: &Args, color: bool) {
+    let no_labels = Symbols::default();
+    let options = assemble_options(args);
+    let interactive = std::io::stdin().is_terminal();
+    let mut line = String::new();
+    let mut line_number = 0;
+
+    loop {
+        if interactive {
+            print!("> ");
+            std::io::stdout().flush().expect("Failed to write prompt");
+        }
+        line.clear();
+        if std::io::stdin().read_line(&mut line).expect("Failed to read stdin") == 0 {
+            break;
+        }
+        line_number += 1;
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+
+        let hex_word = input.starts_with("0x") || input.starts_with("0X") || (input.len() == 8 && input.chars().all(|c| c.is_ascii_hexdigit()));
+        if hex_word {
+            match parse_hex_word(input).map(|instruction| (instruction, assembler.disassemble_word(instruction))) {
+                Ok((instruction, Ok(result))) => println!("{}", if color { assembler.colorize(instruction, &result) } else { result }),
+                Ok((_, Err(err))) => eprintln!("{}", err),
+                Err(err) => eprintln!("Invalid hex word {:?}: {}", input, err),
+            }
+            continue;
+        }
 
-    for asm_line in result.lines() {
-        // Actual machine code
-        let mhc_line = assemble_line(j_codes, i_codes, r_codes, registers, asm_line);
-        println!("{:032b}", mhc_line);
-        mhc_file.write_all(&mhc_line.to_le_bytes()).expect("Failed to write line");
-        // Human-readable 0s and 1s (characters)
-        let bin_line = format!("{:032b}", mhc_line);
-        binary_file.write(bin_line.as_bytes()).expect("Failed to write line");
+        let assembled = if args.lenient_registers {
+            assembler.assemble_lenient_line(input, text_base(args), &options, &no_labels)
+        } else {
+            assembler.assemble_line(input, text_base(args), &options, &no_labels)
+        };
+        match assembled {
+            Ok(words) => for word in words {
+                println!("0x{:08x} {:032b}", word, word);
+            },
+            Err(err) => eprintln!("{}", err.at(line_number, line.trim())),
+        }
     }
 }
 
-fn assemble_line(j_codes: &BiMap<&str, u32>, i_codes: &BiMap<&str, u32>, r_codes: &BiMap<&str, u32>, registers: &BiMap<&str, u32>, asm_line: &str) -> u32 {
-    let asm_line = if let Some(split) = asm_line.split_once("#") {
-        split.0
-    } else {
-        asm_line
-    }.trim();
+// Resolves --direction and --format, or the deprecated --mode, to what to do and with which format
+fn direction_and_format(args: &Args) -> (Direction, Format) {
+    let (direction, format) = match &args.mode {
+        Some(mode) => {
+            eprintln!("Warning: --mode is deprecated, use --direction and --format instead");
+            match mode {
+                AssemblerMode::Assemble => (Direction::Assemble, args.format),
+                AssemblerMode::Bin => (Direction::Disassemble, Some(Format::Bin)),
+                AssemblerMode::Mhc => (Direction::Disassemble, Some(Format::Mhc)),
+            }
+        }
+        None => (args.direction.unwrap_or(Direction::Assemble), args.format),
+    };
 
-    // split on spaces or commas, filter empty substrings, put into array
-    let parts: Vec<&str> = asm_line
-        .split(|c| c == ',' || c == ' ')
-        .filter(|str| !str.is_empty())
-        .collect();
-    let instruction = parts[0].to_ascii_lowercase();
-    let instruction = instruction.as_str();
-    println!("{:?}", parts);
-
-    if let Some(i_opcode) = i_codes.get_by_left(instruction) {
-        assemble_i(*i_opcode, registers, parts)
-    } else if let Some(r_opcode) = r_codes.get_by_left(instruction) {
-        assemble_r(*r_opcode, registers, parts)
-    } else if let Some(j_opcode) = j_codes.get_by_left(instruction) {
-        assemble_j(*j_opcode)
-    } else {
-        println!("Failed to parse line {:?}", parts);
-        0
+    match direction {
+        Direction::Assemble => (direction, format.unwrap_or(Format::Files)),
+        Direction::Disassemble => {
+            let format = format.unwrap_or(Format::Mhc);
+            if !matches!(format, Format::Bin | Format::Mhc | Format::Hex) {
+                Args::command().error(ErrorKind::InvalidValue, "only bin, mhc and hex can be disassembled").exit();
+            }
+            (direction, format)
+        }
+    }
+}
+
+// Disassembles words given on the command line, reporting bad ones without stopping
+fn decode_words(assembler: &Assembler, words: &[String], color: bool, pretty: bool) {
+    for (index, word) in words.iter().enumerate() {
+        match parse_hex_word(word) {
+            Ok(instruction) => match assembler.disassemble_word(instruction).map(|result| if pretty { align_operands(&result) } else { result }) {
+                Ok(result) => println!("{}: {}", index, if color { assembler.colorize(instruction, &result) } else { result }),
+                Err(err) => eprintln!("{}: {}", index, err),
+            },
+            Err(err) => eprintln!("{}: invalid hex word {:?}: {}", index, word, err),
+        }
+    }
+}
+
+// "addi $t0, $t1, 4" becomes "addi   $t0, $t1, 4", with the mnemonic padded to MNEMONIC_WIDTH
+fn align_operands(line: &str) -> String {
+    match line.split_once(' ') {
+        Some((mnemonic, operands)) => format!("{:<width$} {}", mnemonic, operands, width = MNEMONIC_WIDTH),
+        None => line.to_owned(),
     }
 }
 
-fn assemble_i(opcode: u32, registers: &BiMap<&str, u32>, parts: Vec<&str>) -> u32 {
-    let immediate: u32;
-    let t_register: &u32;
-    let s_register: &u32;
+fn assemble_file(assembler: &Assembler, asm_paths: &[String], args: &Args) -> Result<Program, Vec<AssembleError>> {
+    let source = read_sources(asm_paths, args).map_err(|err| vec![err])?;
+    let lines: Vec<String> = source.iter().map(|line| line.text.clone()).collect();
+    let options = assemble_options(args);
 
-    if opcode == LW_OPCODE || opcode == SW_OPCODE {
-        let last_part_parts: Vec<&str> = parts[2]
-            .split(|c| c == '(' || c == ')')
-            .filter(|str| !str.is_empty())
+    if args.verbose {
+        for parts in lines.iter().filter_map(|asm_line| tokenize(asm_line).ok()).filter(|parts| !parts.is_empty()) {
+            eprintln!("{:?}", parts);
+        }
+    }
+
+    assembler.assemble_program(&lines, &options)
+        .map_err(|errors| errors.into_iter().map(|err| locate(err, &source)).collect::<Vec<_>>())
+}
+
+// Every input file in order, as one program
+fn read_sources(asm_paths: &[String], args: &Args) -> Result<Vec<SourceLine>, AssembleError> {
+    let mut source = Vec::new();
+    for asm_path in asm_paths {
+        source.extend(read_source(Path::new(asm_path), &args.include_dirs, 0)?);
+    }
+
+    Ok(source)
+}
+
+// Turns an error's line number in the combined source back into a line of the file it came from
+fn locate(mut err: AssembleError, source: &[SourceLine]) -> AssembleError {
+    let Some(origin) = err.line_number.checked_sub(1).and_then(|index| source.get(index)) else {
+        return err;
+    };
+
+    err.line_number = origin.line_number;
+    if source.iter().any(|line| line.path != origin.path) {
+        err.file = Some(origin.path.display().to_string());
+    }
+    err
+}
+
+fn parse_address(text: &str) -> Result<u32, String> {
+    let address = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+
+    address.map_err(|err| format!("invalid address {:?}: {}", text, err))
+}
+
+fn text_base(args: &Args) -> u32 {
+    args.base.unwrap_or(DEFAULT_TEXT_BASE)
+}
+
+fn assemble_options(args: &Args) -> AssembleOptions {
+    AssembleOptions {
+        extensions: args.extensions,
+        max_line_len: args.max_line_len,
+        max_image_size: args.max_image_size,
+        text_base: text_base(args),
+        data_base: args.data_base,
+        lenient_registers: args.lenient_registers,
+        big_endian: args.endian == Endian::Big,
+        add_exit: args.add_exit,
+        main_first: args.main_first,
+    }
+}
+
+// Reassembles the disassembly of each text word on its own and reports the first one that comes back different
+fn verify_round_trip(assembler: &Assembler, words: &[u32], options: &AssembleOptions) -> bool {
+    let no_labels = Symbols::default();
+
+    for (index, word) in words.iter().enumerate() {
+        let address = options.text_base.wrapping_add(index as u32 * 4);
+        let disassembly = match assembler.disassemble_word(*word) {
+            Ok(disassembly) => disassembly,
+            Err(err) => {
+                eprintln!("Word {} doesn't disassemble: {}", index, err);
+                return false;
+            }
+        };
+
+        match assembler.assemble_line(&disassembly, address, options, &no_labels) {
+            Ok(reassembled) if reassembled == [*word] => {}
+            Ok(reassembled) => {
+                let reassembled: Vec<String> = reassembled.iter().map(|word| format!("0x{:08x}", word)).collect();
+                eprintln!("Word {} (0x{:08x}) disassembles to \"{}\", which assembles to {}", index, word, disassembly, reassembled.join(", "));
+                return false;
+            }
+            Err(err) => {
+                eprintln!("Word {} (0x{:08x}) disassembles to \"{}\", which doesn't assemble: {}", index, word, disassembly, err.message);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// Only text words are counted, classified by their encoding. The size is of the whole image, data and padding too.
+fn print_stats(assembler: &Assembler, text: &[u32], image_words: usize) {
+    let mut counts = [0; 3];
+    let mut other = 0;
+    for word in text {
+        match assembler.instruction_type(*word) {
+            Some(InstructionType::R) => counts[0] += 1,
+            Some(InstructionType::I) => counts[1] += 1,
+            Some(InstructionType::J) => counts[2] += 1,
+            None => other += 1,
+        }
+    }
+
+    eprintln!("Instructions: {} (R: {}, I: {}, J: {})", counts.iter().sum::<usize>(), counts[0], counts[1], counts[2]);
+    if other != 0 {
+        eprintln!("Other words: {}", other);
+    }
+    eprintln!("Bytes: {}", image_words * 4);
+}
+
+fn exit_with_errors(errors: &[AssembleError]) -> ! {
+    for err in errors {
+        eprintln!("{}", err);
+    }
+    eprintln!("Aborting due to {} error{}", errors.len(), if errors.len() == 1 { "" } else { "s" });
+    std::process::exit(1);
+}
+
+// Debug formatting of a str is a valid Rust string literal, so source lines are escaped for free
+fn emit_test(assembler: &Assembler, asm_paths: &[String], args: &Args) {
+    match args.march {
+        March::Mips1 => println!("let assembler = Assembler::new();"),
+        _ => println!("let assembler = Assembler::with_architecture(Architecture::{:?});", args.march.architecture()),
+    }
+    println!("let options = AssembleOptions {{ extensions: {}, big_endian: {}, ..AssembleOptions::default() }};", args.extensions, args.endian == Endian::Big);
+
+    let source = read_sources(asm_paths, args).unwrap_or_else(|err| exit_with_errors(&[err]));
+    let lines: Vec<String> = source.iter().map(|line| line.text.clone()).collect();
+    let options = assemble_options(args);
+    let (symbols, errors) = collect_symbols(&lines, &options);
+    if !errors.is_empty() {
+        exit_with_errors(&errors.into_iter().map(|err| locate(err, &source)).collect::<Vec<_>>());
+    }
+    let mut sorted_labels: Vec<(&String, &u32)> = symbols.labels.iter().collect();
+    sorted_labels.sort_by_key(|(_, address)| **address);
+    let label_entries: Vec<String> = sorted_labels.iter()
+        .map(|(label, address)| format!("({:?}.to_owned(), 0x{:08x})", label, address))
+        .collect();
+    let mut sorted_externals: Vec<(&String, &u32)> = symbols.externals.iter().collect();
+    sorted_externals.sort();
+    let extern_entries: Vec<String> = sorted_externals.iter()
+        .map(|(name, size)| format!("({:?}.to_owned(), {})", name, size))
+        .collect();
+    println!("let symbols = Symbols {{ labels: HashMap::from([{}]), externals: HashMap::from([{}]) }};", label_entries.join(", "), extern_entries.join(", "));
+
+    for (line_index, (asm_line, address)) in lines.iter().zip(line_addresses(&lines, &options)).enumerate() {
+        // The test checks the strict form of the line
+        let asm_line = &if args.lenient_registers {
+            assembler.prefix_registers(asm_line, &symbols).unwrap_or_else(|err| exit_with_errors(&[locate(err.at(line_index + 1, asm_line), &source)]))
+        } else {
+            asm_line.clone()
+        };
+        let line_words = assembler.assemble_line(asm_line, address, &options, &symbols)
+            .unwrap_or_else(|err| exit_with_errors(&[locate(err.at(line_index + 1, asm_line), &source)]));
+        let words: Vec<String> = line_words.iter()
+            .map(|word| format!("0x{:08x}", word))
             .collect();
 
-        t_register = registers.get_by_left(parts[1]).unwrap();
-        immediate = last_part_parts[0].parse::<u32>().expect("Invalid immediate value for lw/sw instruction") & 0xffff;
-        s_register = registers.get_by_left(last_part_parts[1]).unwrap();
+        println!("assert_eq!(assembler.assemble_line({:?}, 0x{:08x}, &options, &symbols).unwrap(), vec![{}]);", asm_line, address, words.join(", "));
+    }
+}
+
+// The whole input file, or standard input when the path is "-"
+fn read_input(path: &Path) -> Vec<u8> {
+    if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut bytes).unwrap_or_else(|err| exit_with_input_error(path, err));
+        bytes
     } else {
-        immediate = parts[3].parse::<u32>().expect("Invalid immediate value for instruction") & 0xffff;
-        t_register = registers.get_by_left(parts[1]).unwrap();
-        s_register = registers.get_by_left(parts[2]).unwrap();
+        std::fs::read(path).unwrap_or_else(|err| exit_with_input_error(path, err))
     }
+}
+
+// The input as text, for the formats made of lines
+fn read_text(path: &Path) -> String {
+    String::from_utf8(read_input(path)).unwrap_or_else(|err| exit_with_input_error(path, err))
+}
 
-    immediate | (s_register << 16) | (t_register << 21) | (opcode << 26)
+// A file that can't be read isn't a bug, so it is reported like an assembly error instead of panicking
+fn exit_with_input_error(path: &Path, err: impl std::fmt::Display) -> ! {
+    eprintln!("error: {}: {}", path.display(), err);
+    std::process::exit(1);
 }
 
-fn disassemble_i(instruction: u32, registers: &BiMap<&str, u32>, instruction_name: &str) -> String {
-    let t_register = registers.get_by_right(&((instruction >> 21) & 0b11111)).unwrap();
-    let s_register = registers.get_by_right(&((instruction >> 16) & 0b11111)).unwrap();
-    let immediate = instruction & 0xffff;
+// A line of source and where it came from, since includes and multiple inputs are merged into one program
+struct SourceLine {
+    text: String,
+    path: PathBuf,
+    line_number: usize,
+}
 
-    if instruction_name.eq("lw") || instruction_name.eq("sw") {
-        format!("{} {}, {}({})\n", instruction_name, t_register, immediate, s_register)
-    } else {
-        format!("{} {}, {}, {}\n", instruction_name, t_register, s_register, immediate)
+// Reads a source file, replacing each `.include "file"` line with the lines of that file
+fn read_source(path: &Path, include_dirs: &[PathBuf], depth: usize) -> Result<Vec<SourceLine>, AssembleError> {
+    let source = read_text(path);
+    let mut lines = Vec::new();
+
+    // Problems with includes are always reported with the file they are in
+    let in_file = |mut err: AssembleError| {
+        err.file = Some(path.display().to_string());
+        err
+    };
+
+    for (line_index, line) in source.lines().enumerate() {
+        // A line that doesn't tokenize is left for the assembler to report
+        let spans = tokenize_spans(line).unwrap_or_default();
+        let tokens: Vec<&str> = spans.iter().map(|span| &line[span.clone()]).collect();
+
+        if tokens.first().is_some_and(|token| token.eq_ignore_ascii_case(".include")) {
+            let file_name = match tokens[1..] {
+                [file_name] => file_name.strip_prefix('"').and_then(|file_name| file_name.strip_suffix('"')),
+                _ => None,
+            }.ok_or_else(|| in_file(AssembleError::new("expected a quoted file name after .include".to_owned()).at(line_index + 1, line)))?;
+            if depth == MAX_INCLUDE_DEPTH {
+                return Err(in_file(AssembleError::new(format!("includes nested more than {} deep", MAX_INCLUDE_DEPTH)).at(line_index + 1, line)));
+            }
+            let included = resolve_include(path, file_name, include_dirs).map_err(|err| in_file(err.span(spans[1].clone()).at(line_index + 1, line)))?;
+
+            lines.extend(read_source(&included, include_dirs, depth + 1)?);
+        } else {
+            lines.push(SourceLine { text: line.to_owned(), path: path.to_path_buf(), line_number: line_index + 1 });
+        }
     }
+
+    Ok(lines)
 }
 
-fn assemble_r(func_code: u32, registers: &BiMap<&str, u32>, parts: Vec<&str>) -> u32 {
-    let shift_opcode = func_code == SLL_OPCODE || func_code == SRL_OPCODE || func_code == SRA_OPCODE;
-    let shift_amount = if shift_opcode {
-        parts[3].parse::<u32>().expect(&format!("Invalid shift amount: {}", parts[3]))
+// Looks next to the including file first, then in each -I directory in order
+fn resolve_include(including_path: &Path, file_name: &str, include_dirs: &[PathBuf]) -> Result<PathBuf, AssembleError> {
+    let own_dir = including_path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let searched: Vec<PathBuf> = std::iter::once(own_dir)
+        .chain(include_dirs.iter().cloned())
+        .map(|dir| dir.join(file_name))
+        .collect();
+
+    if let Some(found) = searched.iter().find(|candidate| candidate.is_file()) {
+        return Ok(found.clone());
+    }
+
+    let searched: Vec<String> = searched.iter().map(|candidate| candidate.display().to_string()).collect();
+    Err(AssembleError::new(format!("could not find included file {:?}, searched: {}", file_name, searched.join(", "))))
+}
+
+// Writes whichever of the .bin and .mhc files are given a path
+fn write_files(assembler: &Assembler, words: &[u32], binary_path: Option<&str>, mhc_path: Option<&str>, args: &Args) {
+    // Actual machine code
+    if let Some(mhc_path) = mhc_path {
+        std::fs::write(mhc_path, mhc_file_bytes(words, args)).expect("Failed to write MHC file");
+    }
+
+    if args.verbose {
+        for (index, mhc_line) in words.iter().enumerate() {
+            println!("{}{:032b}", word_number(index, args.number_words), mhc_line);
+        }
+    }
+
+    if let Some(binary_path) = binary_path {
+        let binary_file = File::create(binary_path).expect("Failed to create binary file");
+        let mut binary_file = std::io::BufWriter::new(binary_file);
+
+        for mhc_line in words {
+            // Human-readable 0s and 1s (characters), one word per line
+            if args.annotate_bin {
+                writeln!(binary_file, "{}", assembler.grouped_bits(*mhc_line))
+            } else {
+                writeln!(binary_file, "{:032b}", mhc_line)
+            }.expect("Failed to write line");
+        }
+    }
+}
+
+// Everything in a .mhc file, so --checksum covers exactly what is written
+fn mhc_file_bytes(words: &[u32], args: &Args) -> Vec<u8> {
+    let big_endian = args.endian == Endian::Big;
+    let mut bytes = if args.headerless { Vec::new() } else { mhc_header(words.len(), big_endian, text_base(args)) };
+
+    bytes.extend(mhc_bytes(words, big_endian));
+    bytes
+}
+
+// One word per line in the format $readmemh (or $readmemb) loads, optionally commented with its word index
+fn write_verilog(words: &[u32], mem_path: &str, binary: bool, number_words: bool) {
+    let mem_file = File::create(mem_path).expect("Failed to create memory file");
+    let mut mem_file = std::io::BufWriter::new(mem_file);
+
+    for (index, word) in words.iter().enumerate() {
+        let word = if binary { format!("{:032b}", word) } else { format!("{:08x}", word) };
+        if number_words {
+            writeln!(mem_file, "{} // {}", word, index)
+        } else {
+            writeln!(mem_file, "{}", word)
+        }.expect("Failed to write memory file");
+    }
+}
+
+// The header Logisim's ROM and RAM components look for, then the words as hex
+fn write_logisim(words: &[u32], image_path: &str) {
+    let image_file = File::create(image_path).expect("Failed to create image file");
+    let mut image_file = std::io::BufWriter::new(image_file);
+
+    writeln!(image_file, "v2.0 raw").expect("Failed to write image file");
+    for word in words {
+        writeln!(image_file, "{:08x}", word).expect("Failed to write image file");
+    }
+}
+
+// Each word with its byte address, every segment counting up from its own base address
+fn write_mars(segments: &[(u32, &[u32])], dump_path: &str) {
+    let dump_file = File::create(dump_path).expect("Failed to create dump file");
+    let mut dump_file = std::io::BufWriter::new(dump_file);
+
+    for (base_address, words) in segments {
+        for (index, word) in words.iter().enumerate() {
+            writeln!(dump_file, "0x{:08x} 0x{:08x}", base_address.wrapping_add(index as u32 * 4), word).expect("Failed to write dump file");
+        }
+    }
+}
+
+// "label 0xADDR" per line, in address order
+fn write_symbols(labels: &HashMap<String, u32>, symbols_path: &Path) {
+    let mut sorted_labels: Vec<(&String, &u32)> = labels.iter().collect();
+    sorted_labels.sort_by_key(|(label, address)| (**address, *label));
+
+    let symbols_file = File::create(symbols_path).expect("Failed to create symbol file");
+    let mut symbols_file = std::io::BufWriter::new(symbols_file);
+    for (label, address) in sorted_labels {
+        writeln!(symbols_file, "{} 0x{:08x}", label, address).expect("Failed to write symbol file");
+    }
+}
+
+// "0xADDR TYPE name" per line, in address order
+fn write_relocations(program: &Program, relocations_path: &Path) {
+    let relocations_file = File::create(relocations_path).expect("Failed to create relocation file");
+    let mut relocations_file = std::io::BufWriter::new(relocations_file);
+    for relocation in &program.relocations {
+        writeln!(relocations_file, "0x{:08x} {} {}", relocation.address, relocation.kind.name(), relocation.symbol).expect("Failed to write relocation file");
+    }
+}
+
+// Maps addresses back to labels. When several labels share an address the first one listed wins.
+fn read_symbols(symbols_path: &Path) -> HashMap<u32, String> {
+    let text = std::fs::read_to_string(symbols_path).unwrap_or_else(|err| exit_with_input_error(symbols_path, err));
+    let mut symbols = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [] => {}
+            [label, address] => match parse_address(address) {
+                Ok(address) => {
+                    symbols.entry(address).or_insert_with(|| label.to_owned());
+                }
+                Err(err) => errors.push(AssembleError::new(err).at(line_index + 1, line)),
+            },
+            _ => errors.push(AssembleError::new("expected \"label 0xADDR\"".to_owned()).at(line_index + 1, line)),
+        }
+    }
+
+    if !errors.is_empty() {
+        exit_with_errors(&errors);
+    }
+    symbols
+}
+
+// One 8-digit hex word per line, for piping into other tools
+fn write_hex(words: &[u32], number_words: bool) {
+    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+
+    for (index, word) in words.iter().enumerate() {
+        writeln!(stdout, "{}{:08x}", word_number(index, number_words), word).expect("Failed to write to stdout");
+    }
+}
+
+// Index column shared by every printed format so lines can be matched to the program counter
+fn word_number(index: usize, number_words: bool) -> String {
+    if number_words {
+        format!("{}: ", index)
     } else {
-        0
-    };
-    let d_register = registers.get_by_left(parts[1]).unwrap();
-    let t_register = registers.get_by_left(parts[2]).unwrap();
-    let s_register = if shift_opcode { &0 } else { registers.get_by_left(parts[3]).unwrap() };
-
-    // no need to specify opcode as it is always zero for R type instructions
-    func_code | (shift_amount << 6) | (d_register << 11) | (t_register << 16) | (s_register << 21)
-}
-
-fn disassemble_r(instruction: u32, registers: &BiMap<&str, u32>, instruction_name: &str) -> String {
-    let d_register = registers.get_by_right(&((instruction >> 11) & 0b11111)).unwrap();
-    let t_register = registers.get_by_right(&((instruction >> 16) & 0b11111)).unwrap();
-    let s_register = registers.get_by_right(&((instruction >> 21) & 0b11111)).unwrap();
-
-    format!("{} {}, {}, {}", instruction_name, d_register, s_register, t_register)
-}
-
-// Not sure how to handle labels. It wasn't in the assembler Dabish gave us.
-fn assemble_j(opcode: u32) -> u32 {
-    opcode << 26
-}
-
-fn disassemble_j(instruction: u32, instruction_name: &&str) -> String {
-    // Sorry. Dabish didn't include labels in his assembler, so we had nothing to go off of
-    format!("{} unimplemented", instruction_name)
-}
-
-// https://www.d.umn.edu/~gshute/mips/jtype.html
-fn create_j_codes<'a>() -> BiMap<&'a str, u32> {
-    BiMap::from_iter([
-        ("j", 0b000010),
-        ("jal", 0b000011)
-    ])
-}
-
-// https://www.d.umn.edu/~gshute/mips/itype.html
-fn create_i_codes<'a>() -> BiMap<&'a str, u32> {
-    BiMap::from_iter([
-        ("addi", 0b001000),
-        ("addiu", 0b001001),
-        ("andi", 0b001100),
-        ("beq", 0b000100),
-        ("bne", 0b000101),
-        ("lw", LW_OPCODE),
-        ("ori", 0b001101),
-        ("sw", SW_OPCODE),
-    ])
-}
-
-// Func codes. Opcode of R-type is always zero
-// https://www.d.umn.edu/~gshute/mips/rtype.html
-fn create_r_codes<'a>() -> BiMap<&'a str, u32> {
-    BiMap::from_iter([
-        ("add", 0b100000),
-        ("addu", 0b100001),
-        ("and", 0b100100),
-        ("div", 0b011010),
-        ("jr", 0b001000),
-        ("nor", 0b100111),
-        ("or", 0b100101),
-        ("sll", SLL_OPCODE),
-        ("sllv", SLLV_OPCODE),
-        ("slt", 0b101010),
-        ("sltu", 0b101011),
-        ("sra", SRA_OPCODE),
-        ("srav", SRAV_OPCODE),
-        ("srl", SRL_OPCODE),
-        ("srlv", SRLV_OPCODE),
-        ("sub", 0b100010),
-        ("subu", 0b100011),
-        ("xor", 0b100110)
-    ])
-}
-
-// All registers (couldn't do aliases with BiMap, but if i used two maps it would complicate the code)
-fn create_register_codes<'a>() -> BiMap<&'a str, u32> {
-    BiMap::from_iter([
-        ("$zero", 0b00000),
-        ("$at", 0b00001),
-        ("$v0", 0b00010),
-        ("$v1", 0b00011),
-        ("$a0", 0b00100),
-        ("$a1", 0b00101),
-        ("$a2", 0b00110),
-        ("$a3", 0b00111),
-        ("$t0", 0b01000),
-        ("$t1", 0b01001),
-        ("$t2", 0b01010),
-        ("$t3", 0b01011),
-        ("$t4", 0b01100),
-        ("$t5", 0b01101),
-        ("$t6", 0b01110),
-        ("$t7", 0b01111),
-        ("$s0", 0b10000),
-        ("$s1", 0b10001),
-        ("$s2", 0b10010),
-        ("$s3", 0b10011),
-        ("$s4", 0b10100),
-        ("$s5", 0b10101),
-        ("$s6", 0b10110),
-        ("$s7", 0b10111),
-        ("$t8", 0b11000),
-        ("$t9", 0b11001),
-        ("$k0", 0b11010),
-        ("$k1", 0b11011),
-        ("$gp", 0b11100),
-        ("$sp", 0b11101),
-        ("$fp", 0b11110),
-        ("$ra", 0b11111),
-    ])
+        String::new()
+    }
 }
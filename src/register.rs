@@ -0,0 +1,106 @@
+// Git repository available on GitHub at https://github.com/thedarkcolour/mips-assembler
+
+// The 32 general purpose registers, in encoding order so a register's number is its discriminant
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Register {
+    Zero, At, V0, V1, A0, A1, A2, A3,
+    T0, T1, T2, T3, T4, T5, T6, T7,
+    S0, S1, S2, S3, S4, S5, S6, S7,
+    T8, T9, K0, K1, Gp, Sp, Fp, Ra,
+}
+
+use Register::*;
+
+const REGISTERS: [Register; 32] = [
+    Zero, At, V0, V1, A0, A1, A2, A3,
+    T0, T1, T2, T3, T4, T5, T6, T7,
+    S0, S1, S2, S3, S4, S5, S6, S7,
+    T8, T9, K0, K1, Gp, Sp, Fp, Ra,
+];
+
+// Canonical names, which the disassembler prints
+const NAMES: [&str; 32] = [
+    "$zero", "$at", "$v0", "$v1", "$a0", "$a1", "$a2", "$a3",
+    "$t0", "$t1", "$t2", "$t3", "$t4", "$t5", "$t6", "$t7",
+    "$s0", "$s1", "$s2", "$s3", "$s4", "$s5", "$s6", "$s7",
+    "$t8", "$t9", "$k0", "$k1", "$gp", "$sp", "$fp", "$ra",
+];
+
+// Other names some assemblers and textbooks use
+const ALIASES: [(&str, Register); 1] = [("$s8", Fp)];
+
+impl Register {
+    // The register in a 5-bit field, ignoring any bits above it
+    pub fn from_field(field: u32) -> Register {
+        REGISTERS[(field & 0b11111) as usize]
+    }
+
+    pub fn number(self) -> u32 {
+        self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        NAMES[self as usize]
+    }
+
+    // Canonical names, aliases and "$0" to "$31", in any case
+    pub fn parse(name: &str) -> Option<Register> {
+        let name = name.to_ascii_lowercase();
+        let name = name.as_str();
+
+        if let Some(index) = NAMES.iter().position(|canonical| *canonical == name) {
+            return Some(REGISTERS[index]);
+        }
+        if let Some((_, register)) = ALIASES.iter().find(|(alias, _)| *alias == name) {
+            return Some(*register);
+        }
+        name.strip_prefix('$')
+            .filter(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|number| number.parse::<u32>().ok())
+            .filter(|number| *number < 32)
+            .map(Register::from_field)
+    }
+}
+
+// FPU (coprocessor 1) registers are only ever numbered, "$f0" to "$f31"
+pub fn parse_fpu_register(name: &str) -> Option<u32> {
+    name.strip_prefix('$')
+        .and_then(|name| name.strip_prefix(['f', 'F']))
+        .filter(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        .and_then(|number| number.parse::<u32>().ok())
+        .filter(|number| *number < 32)
+}
+
+pub fn fpu_register_name(field: u32) -> String {
+    format!("$f{}", field & 0b11111)
+}
+
+// Coprocessor 0 registers with a conventional name. The rest can still be written by number, like "$7".
+const CP0_NAMES: [(&str, u32); 16] = [
+    ("$index", 0), ("$random", 1), ("$entrylo0", 2), ("$entrylo1", 3),
+    ("$context", 4), ("$pagemask", 5), ("$wired", 6), ("$badvaddr", 8),
+    ("$count", 9), ("$entryhi", 10), ("$compare", 11), ("$status", 12),
+    ("$cause", 13), ("$epc", 14), ("$prid", 15), ("$config", 16),
+];
+
+// Named CP0 registers or "$0" to "$31", in any case
+pub fn parse_cp0_register(name: &str) -> Option<u32> {
+    let name = name.to_ascii_lowercase();
+
+    if let Some((_, number)) = CP0_NAMES.iter().find(|(canonical, _)| *canonical == name) {
+        return Some(*number);
+    }
+    name.strip_prefix('$')
+        .filter(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        .and_then(|number| number.parse::<u32>().ok())
+        .filter(|number| *number < 32)
+}
+
+pub fn cp0_register_name(field: u32) -> String {
+    let field = field & 0b11111;
+
+    match CP0_NAMES.iter().find(|(_, number)| *number == field) {
+        Some((name, _)) => (*name).to_owned(),
+        None => format!("${}", field),
+    }
+}
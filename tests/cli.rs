@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use mips_assembler::{AssembleOptions, Assembler, Symbols};
+
+// Runs the binary with stdout and stderr captured, so stdout is never a terminal
+fn mips_assembler(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mips-assembler")).args(args).output().expect("Failed to run mips-assembler")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).expect("stdout is not valid UTF-8")
+}
+
+// A fresh directory per test, since the tests run in parallel
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mips-assembler-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Failed to create test directory");
+    dir
+}
+
+fn write_source(dir: &Path, name: &str, source: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, source).expect("Failed to write test source");
+    path.to_str().expect("Test path is not valid UTF-8").to_owned()
+}
+
+#[test]
+fn piped_output_is_hex_words_and_files_can_be_forced() {
+    let dir = temp_dir("piped");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\n");
+
+    let output = mips_assembler(&["-i", &source]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "012a4020\n8fa80004\n");
+    assert!(!dir.join("prog.asm.bin").exists() && !dir.join("prog.asm.mhc").exists());
+
+    let output = mips_assembler(&["-i", &source, "--files"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert!(dir.join("prog.asm.bin").exists() && dir.join("prog.asm.mhc").exists());
+}
+
+#[test]
+fn number_words_prefixes_each_word_with_its_index() {
+    let dir = temp_dir("number-words");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\nsll $t0, $t1, 2\n");
+
+    let output = mips_assembler(&["-i", &source, "--number-words"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "0: 012a4020\n1: 8fa80004\n2: 00094080\n");
+
+    // Verilog can't take the prefix, so the same index goes in a comment
+    assert!(mips_assembler(&["-i", &source, "--number-words", "--format", "verilog"]).status.success());
+    let mem = fs::read_to_string(dir.join("prog.asm.mem")).expect("Failed to read memory file");
+    assert_eq!(mem, "012a4020 // 0\n8fa80004 // 1\n00094080 // 2\n");
+}
+
+#[test]
+fn decode_reports_bad_words_without_stopping() {
+    let output = mips_assembler(&["--decode", "0x20080005", "zz", "0x00a62020"]);
+
+    assert_eq!(stdout(&output), "0: addi $t0, $zero, 5\n2: add $a0, $a1, $a2\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "1: invalid hex word \"zz\": invalid digit found in string\n");
+}
+
+#[test]
+fn checksum_is_the_crc32_of_the_mhc_file() {
+    let dir = temp_dir("checksum");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\nj 0x00400000\n");
+
+    let output = mips_assembler(&["-i", &source, "--checksum", "--format", "mhc"]);
+    assert!(output.status.success());
+    // The same value zlib.crc32 gives for prog.asm.mhc
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "CRC32: c0a37049\n");
+    assert!(dir.join("prog.asm.mhc").exists());
+}
+
+#[test]
+fn fields_breaks_down_an_add() {
+    let output = mips_assembler(&["--fields", "0x00a62020"]);
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "opcode=0x00 rs=$a1(5) rt=$a2(6) rd=$a0(4) shamt=0 funct=0x20 (add)\n");
+}
+
+#[test]
+fn color_never_writes_plain_disassembly() {
+    let dir = temp_dir("color");
+    let words = write_source(&dir, "words.txt", "00a62020\n8fbffffc\n08100000\n");
+
+    let output = mips_assembler(&["-d", "disassemble", "--format", "hex", "-i", &words, "--color", "never"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "add $a0, $a1, $a2\nlw $ra, -4($sp)\nj 0x00400000\n");
+
+    // The codes really are there when asked for
+    let output = mips_assembler(&["-d", "disassemble", "--format", "hex", "-i", &words, "--color", "always"]);
+    assert!(stdout(&output).contains('\x1b'));
+}
+
+#[test]
+fn includes_are_found_through_search_directories() {
+    let dir = temp_dir("include");
+    fs::create_dir_all(dir.join("lib")).expect("Failed to create include directory");
+    write_source(&dir.join("lib"), "exit.asm", "addi $v0, $zero, 10\n");
+    let source = write_source(&dir, "main.asm", "nop\n.include \"exit.asm\"\n");
+
+    let output = mips_assembler(&["-i", &source]);
+    assert!(!output.status.success());
+    let searched = dir.join("exit.asm");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("searched: {}", searched.display())));
+    // The caret is under the file name
+    assert!(stderr.contains("\n    .include \"exit.asm\"\n             ^^^^^^^^^^"), "{}", stderr);
+
+    let lib = dir.join("lib");
+    let output = mips_assembler(&["-i", &source, "-I", lib.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "00000000\n2002000a\n");
+}
+
+#[test]
+fn include_is_a_case_insensitive_directive_with_a_quoted_path() {
+    let dir = temp_dir("include-quoted");
+    write_source(&dir, "exit;v#2.asm", "addi $v0, $zero, 10\n");
+    let source = write_source(&dir, "main.asm", ".INCLUDE \"exit;v#2.asm\" # the exit\nnop\n");
+
+    let output = mips_assembler(&["-i", &source]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "2002000a\n00000000\n");
+
+    // Only the whole mnemonic counts
+    let source = write_source(&dir, "bad.asm", ".includefoo \"exit;v#2.asm\"\n");
+    let output = mips_assembler(&["-i", &source]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown directive .includefoo"));
+}
+
+// The snippet --emit-test printed for emit_test.asm, checked the way a pasted one would be
+#[test]
+fn emitted_test_snippet_passes() {
+    include!("fixtures/emit_test.rs")
+}
+
+#[test]
+fn emit_test_prints_the_fixture_snippet() {
+    let output = mips_assembler(&["-i", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/emit_test.asm"), "--emit-test"]);
+    assert!(output.status.success());
+
+    let fixture = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/emit_test.rs")).expect("Failed to read fixture");
+    let snippet = fixture.lines().skip(2).take_while(|line| *line != "}").map(|line| format!("{}\n", line)).collect::<String>();
+    assert_eq!(stdout(&output), snippet);
+}
+
+#[test]
+fn disassembling_leaves_the_input_intact() {
+    let dir = temp_dir("disassemble-input");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\n");
+    assert!(mips_assembler(&["-i", &source, "--format", "mhc"]).status.success());
+    let mhc_path = dir.join("prog.asm.mhc");
+    let mhc = fs::read(&mhc_path).expect("Failed to read .mhc file");
+
+    let output = mips_assembler(&["-d", "disassemble", "-i", mhc_path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "add $t0, $t1, $t2\nlw $t0, 4($sp)\n");
+    assert_eq!(fs::read(&mhc_path).expect("Failed to read .mhc file"), mhc);
+}
+
+#[test]
+fn bin_file_has_one_line_of_bits_per_word() {
+    let dir = temp_dir("bin-lines");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\nj 0x00400000\n");
+    assert!(mips_assembler(&["-i", &source, "--format", "bin"]).status.success());
+
+    let bin = fs::read_to_string(dir.join("prog.asm.bin")).expect("Failed to read .bin file");
+    let lines: Vec<&str> = bin.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(bin.ends_with('\n'));
+    assert!(lines.iter().all(|line| line.len() == 32 && line.chars().all(|c| c == '0' || c == '1')));
+    assert_eq!(lines[0], "00000001001010100100000000100000");
+}
+
+#[test]
+fn disassembly_is_one_line_per_word() {
+    let dir = temp_dir("disassembly-lines");
+    let words = write_source(&dir, "words.txt", "012a4020\n8fa80004\n00094080\n2210ffff\n08100000\n");
+
+    let output = mips_assembler(&["-d", "disassemble", "--format", "hex", "-i", &words]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "add $t0, $t1, $t2\nlw $t0, 4($sp)\nsll $t0, $t1, 2\naddi $s0, $s0, -1\nj 0x00400000\n");
+}
+
+#[test]
+fn no_bin_writes_only_the_mhc() {
+    let dir = temp_dir("no-bin");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\nj 0x00400000\n");
+
+    // The 16 byte header, then the words
+    let output = mips_assembler(&["-i", &source, "--files", "--no-bin"]);
+    assert!(output.status.success());
+    assert!(!dir.join("prog.asm.bin").exists());
+    let mhc = fs::read(dir.join("prog.asm.mhc")).expect("Failed to read .mhc file");
+    assert_eq!(mhc.len(), 16 + 4 * 3);
+    assert_eq!(mhc[12..16], 3u32.to_le_bytes());
+    assert_eq!(mhc[16..20], 0x012a4020u32.to_le_bytes());
+
+    // Only the words
+    let output = mips_assembler(&["-i", &source, "--files", "--no-bin", "--headerless"]);
+    assert!(output.status.success());
+    let mhc = fs::read(dir.join("prog.asm.mhc")).expect("Failed to read .mhc file");
+    assert_eq!(mhc.len(), 4 * 3);
+    assert_eq!(mhc[..4], 0x012a4020u32.to_le_bytes());
+
+    let dir = temp_dir("no-mhc");
+    let source = write_source(&dir, "prog.asm", "nop\n");
+    assert!(mips_assembler(&["-i", &source, "--files", "--no-mhc"]).status.success());
+    assert!(dir.join("prog.asm.bin").exists() && !dir.join("prog.asm.mhc").exists());
+}
+
+#[test]
+fn truncated_words_are_padded_not_a_panic() {
+    let dir = temp_dir("truncated");
+    let path = dir.join("six.mhc");
+    fs::write(&path, [0x20, 0x40, 0x2a, 0x01, 0x00, 0x00]).expect("Failed to write test input");
+
+    let output = mips_assembler(&["-d", "disassemble", "-i", path.to_str().unwrap(), "--headerless"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "add $t0, $t1, $t2\nnop\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Warning: the last 2 bytes, from offset 0x4, don't make a whole word and were padded with zeros\n");
+}
+
+#[test]
+fn pad_to_appends_zero_words() {
+    let dir = temp_dir("pad-to");
+    let source = write_source(&dir, "prog.asm", "add $t0, $t1, $t2\nlw $t0, 4($sp)\nj 0x00400000\n");
+
+    let output = mips_assembler(&["-i", &source, "--pad-to", "5"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "012a4020\n8fa80004\n08100000\n00000000\n00000000\n");
+
+    let output = mips_assembler(&["-i", &source, "--pad-to", "2"]);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "The program is 3 words, which doesn't fit in --pad-to 2\n");
+}
+
+#[test]
+fn unreadable_input_is_an_error_not_a_panic() {
+    let dir = temp_dir("missing-input");
+    let missing = dir.join("missing.asm");
+
+    let output = mips_assembler(&["-i", missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(&format!("error: {}: ", missing.display())), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+
+    let source = dir.join("latin1.asm");
+    fs::write(&source, b"nop # caf\xe9\n").expect("Failed to write test source");
+    let output = mips_assembler(&["-i", source.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with(&format!("error: {}: invalid utf-8", source.display())));
+}
@@ -0,0 +1,27 @@
+use std::fs;
+use std::process::{Command, Output};
+
+fn mips_assembler(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mips-assembler")).args(args).output().expect("Failed to run mips-assembler")
+}
+
+// Encodings checked against MARS: rs is bits 21-25, rt is bits 16-20
+#[test]
+fn rs_and_rt_use_the_mips_field_order() {
+    let path = std::env::temp_dir().join(format!("mips-assembler-encoding-{}.asm", std::process::id()));
+    fs::write(&path, "add $t1, $t2, $t3\nsub $t4, $t5, $t6\naddi $t0, $s0, 5\nlw $s1, 4($s2)\n").expect("Failed to write test source");
+
+    let output = mips_assembler(&["-i", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "014b4820\n01ae6022\n22080005\n8e510004\n");
+}
+
+#[test]
+fn decoding_reads_rs_and_rt_from_their_fields() {
+    let output = mips_assembler(&["--decode", "014b4820", "22080005", "8e510004"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(stdout.contains("0: add $t1, $t2, $t3"));
+    assert!(stdout.contains("1: addi $t0, $s0, 5"));
+    assert!(stdout.contains("2: lw $s1, 4($s2)"));
+}
@@ -0,0 +1,4 @@
+main: la $a0, message
+beq $a0, $zero, main  # never
+.data
+message: .asciiz "say \"hi\"\n"
@@ -0,0 +1,10 @@
+// Generated by --emit-test from emit_test.asm, wrapped in a block so include! takes it as one expression
+{
+let assembler = Assembler::new();
+let options = AssembleOptions { extensions: false, big_endian: false, ..AssembleOptions::default() };
+let symbols = Symbols { labels: HashMap::from([("main".to_owned(), 0x00400000), ("message".to_owned(), 0x0040000c)]), externals: HashMap::from([]) };
+assert_eq!(assembler.assemble_line("main: la $a0, message", 0x00400000, &options, &symbols).unwrap(), vec![0x3c010040, 0x3424000c]);
+assert_eq!(assembler.assemble_line("beq $a0, $zero, main  # never", 0x00400008, &options, &symbols).unwrap(), vec![0x1080fffd]);
+assert_eq!(assembler.assemble_line(".data", 0x0040000c, &options, &symbols).unwrap(), vec![]);
+assert_eq!(assembler.assemble_line("message: .asciiz \"say \\\"hi\\\"\\n\"", 0x0040000c, &options, &symbols).unwrap(), vec![0x20796173, 0x22696822, 0x0000000a]);
+}